@@ -0,0 +1,193 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Cursor, Read, Write},
+    path::Path,
+    sync::OnceLock,
+};
+
+use crate::temp_file::TempFile;
+
+/// Body of a request, in memory or in a temporary file when it is larger than `HttpServerConfig::max_body_in_memory`
+pub(crate) enum RequestBody {
+    Memory(Vec<u8>),
+    File {
+        file: TempFile,
+        size: u64,
+        /// Content of the file once `HttpRequest::body()` loaded it
+        loaded: OnceLock<Vec<u8>>,
+    },
+}
+
+/// Reader of a request body returned by `HttpRequest::body_reader()`, it does not load a body kept in a temporary file in memory
+pub struct BodyReader<'a> {
+    inner: ReaderSource<'a>,
+}
+
+enum ReaderSource<'a> {
+    Memory(Cursor<&'a [u8]>),
+    File(BufReader<File>),
+}
+
+/// Destination of a request body while it is received, the bytes move to a temporary file once they exceed `memory_limit`
+pub(crate) struct BodyWriter<'a> {
+    memory: Vec<u8>,
+    file: Option<(TempFile, BufWriter<File>)>,
+    size: u64,
+    memory_limit: Option<usize>,
+    temp_dir: &'a Path,
+}
+
+impl RequestBody {
+    /// The whole body in memory, a body in a temporary file is read on the first call
+    pub(crate) fn bytes(&self) -> &Vec<u8> {
+        match self {
+            RequestBody::Memory(data) => data,
+            RequestBody::File { file, loaded, .. } => {
+                loaded.get_or_init(|| fs::read(file.path()).expect("The temporary file of a request body cannot be read"))
+            }
+        }
+    }
+
+    pub(crate) fn reader(&self) -> io::Result<BodyReader<'_>> {
+        let inner = match self {
+            RequestBody::Memory(data) => ReaderSource::Memory(Cursor::new(data.as_slice())),
+            RequestBody::File { loaded, .. } if loaded.get().is_some() => ReaderSource::Memory(Cursor::new(self.bytes().as_slice())),
+            RequestBody::File { file, .. } => ReaderSource::File(BufReader::new(File::open(file.path())?)),
+        };
+        Ok(BodyReader { inner })
+    }
+
+    pub(crate) fn size(&self) -> u64 {
+        match self {
+            RequestBody::Memory(data) => data.len() as u64,
+            RequestBody::File { size, .. } => *size,
+        }
+    }
+}
+
+impl Read for BodyReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.inner {
+            ReaderSource::Memory(reader) => reader.read(buf),
+            ReaderSource::File(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl BufRead for BodyReader<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match &mut self.inner {
+            ReaderSource::Memory(reader) => reader.fill_buf(),
+            ReaderSource::File(reader) => reader.fill_buf(),
+        }
+    }
+
+    fn consume(&mut self, amount: usize) {
+        match &mut self.inner {
+            ReaderSource::Memory(reader) => reader.consume(amount),
+            ReaderSource::File(reader) => reader.consume(amount),
+        }
+    }
+}
+
+impl<'a> BodyWriter<'a> {
+    /// Keep at most `memory_limit` bytes in memory (`None` for no limit), larger bodies are written to a file in `temp_dir`
+    pub(crate) fn new(memory_limit: Option<usize>, temp_dir: &'a Path) -> Self {
+        Self {
+            memory: Vec::new(),
+            file: None,
+            size: 0,
+            memory_limit,
+            temp_dir,
+        }
+    }
+
+    pub(crate) fn finish(self) -> io::Result<RequestBody> {
+        match self.file {
+            Some((file, writer)) => {
+                writer.into_inner().map_err(|e| e.into_error())?;
+                Ok(RequestBody::File {
+                    file,
+                    size: self.size,
+                    loaded: OnceLock::new(),
+                })
+            }
+            None => Ok(RequestBody::Memory(self.memory)),
+        }
+    }
+}
+
+impl Write for BodyWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.file.is_none() && self.memory_limit.is_some_and(|limit| self.memory.len() + data.len() > limit) {
+            let (file, handle) = TempFile::create(self.temp_dir, "speed-rs-body")?;
+            let mut writer = BufWriter::new(handle);
+            writer.write_all(&self.memory)?;
+            self.memory = Vec::new();
+            self.file = Some((file, writer));
+        }
+        match &mut self.file {
+            Some((_, writer)) => writer.write_all(data)?,
+            None => self.memory.extend_from_slice(data),
+        }
+        self.size += data.len() as u64;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some((_, writer)) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn store(data: &[&[u8]], memory_limit: Option<usize>) -> RequestBody {
+        let temp_dir = env::temp_dir();
+        let mut writer = BodyWriter::new(memory_limit, &temp_dir);
+        for chunk in data {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    fn read_all(body: &RequestBody) -> Vec<u8> {
+        let mut data = Vec::new();
+        body.reader().unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn small_bodies_stay_in_memory() {
+        let body = store(&[b"abc", b"def"], Some(6));
+        assert!(matches!(body, RequestBody::Memory(_)));
+        assert_eq!((body.bytes().as_slice(), body.size()), (&b"abcdef"[..], 6));
+        assert_eq!(read_all(&body), b"abcdef");
+        assert!(matches!(store(&[&[0; 4096]], None), RequestBody::Memory(_)));
+    }
+
+    #[test]
+    fn large_bodies_move_to_a_file() {
+        let body = store(&[b"abc", b"def", b"g"], Some(6));
+        let RequestBody::File { file, loaded, .. } = &body else {
+            panic!("the body was kept in memory");
+        };
+        let path = file.path().to_path_buf();
+        assert_eq!(fs::read(&path).unwrap(), b"abcdefg");
+        assert_eq!(body.size(), 7);
+
+        // the reader streams from the file until `bytes()` loads it
+        assert_eq!(read_all(&body), b"abcdefg");
+        assert!(loaded.get().is_none());
+        assert_eq!(body.bytes(), b"abcdefg");
+        assert_eq!(read_all(&body), b"abcdefg");
+
+        drop(body);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,180 @@
+use std::io::{self, BufRead, Read, Write};
+
+use crate::{parser::{copy_exact, read_line_limited, CopyError, LineError}, HeaderMap};
+
+/// Maximum size of a chunk written by `write_chunked_body()`
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// Longest chunk-size line (with its extensions) accepted by `read_chunked_body()`
+const CHUNK_LINE_LIMIT: usize = 1024;
+
+/// Why a chunked body could not be decoded
+pub(crate) enum ChunkedError {
+    Io(io::Error),
+    /// Writing the decoded body failed
+    Write(io::Error),
+    Invalid,
+    TooLarge,
+}
+
+impl From<LineError> for ChunkedError {
+    fn from(e: LineError) -> Self {
+        match e {
+            LineError::Io(e) => ChunkedError::Io(e),
+            LineError::TooLong => ChunkedError::Invalid,
+        }
+    }
+}
+
+/// Decode a `Transfer-Encoding: chunked` message body (RFC 9112 section 7.1).
+///
+/// Chunk extensions are skipped. The decoded body is written to `body` and the trailer lines are returned.
+/// The body may not exceed `max_size` bytes and the trailer section `max_trailer_size` bytes.
+pub(crate) fn read_chunked_body<R: BufRead, W: Write>(reader: &mut R, body: &mut W, max_size: Option<usize>, max_trailer_size: usize) -> Result<Vec<String>, ChunkedError> {
+    let mut body_size: usize = 0;
+
+    loop {
+        let line = read_line(reader, CHUNK_LINE_LIMIT)?;
+
+        // chunk-size [ chunk-ext ], the extensions are not used by the server
+        let size = line.split(';').next().unwrap_or("").trim();
+        if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ChunkedError::Invalid);
+        }
+        let size = usize::from_str_radix(size, 16).map_err(|_| ChunkedError::TooLarge)?;
+
+        if size == 0 {
+            break;
+        }
+        if max_size.is_some_and(|max| body_size.saturating_add(size) > max) {
+            return Err(ChunkedError::TooLarge);
+        }
+
+        copy_exact(reader, body, size).map_err(|e| match e {
+            CopyError::Read(e) => ChunkedError::Io(e),
+            CopyError::Write(e) => ChunkedError::Write(e),
+        })?;
+        body_size += size;
+
+        // every chunk data is followed by CRLF
+        if !read_line(reader, 0)?.is_empty() {
+            return Err(ChunkedError::Invalid);
+        }
+    }
+
+    // trailer section ends with an empty line
+    let mut trailers = Vec::<String>::new();
+    let mut trailer_size: usize = 0;
+    loop {
+        let line = read_line(reader, max_trailer_size.saturating_sub(trailer_size))?;
+        if line.is_empty() {
+            break;
+        }
+        trailer_size += line.len();
+        trailers.push(line);
+    }
+
+    Ok(trailers)
+}
+
+/// Read one line without its line ending, an early end of stream is an error
+fn read_line<R: BufRead>(reader: &mut R, limit: usize) -> Result<String, ChunkedError> {
+    match read_line_limited(reader, limit)? {
+        Some(line) => Ok(line),
+        None => Err(ChunkedError::Io(io::Error::from(io::ErrorKind::UnexpectedEof))),
+    }
+}
+
+/// Encode `reader` as a `Transfer-Encoding: chunked` message body followed by the `trailers`.
+pub(crate) fn write_chunked_body<R: Read + ?Sized, W: Write>(writer: &mut W, reader: &mut R, trailers: &HeaderMap) -> io::Result<()> {
+    let mut buffer = vec![0; CHUNK_SIZE];
+
+    loop {
+        let size = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(size) => size,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        write!(writer, "{:X}\r\n", size)?;
+        writer.write_all(&buffer[..size])?;
+        writer.write_all(b"\r\n")?;
+    }
+
+    // last chunk, trailer section and the final CRLF
+    writer.write_all(b"0\r\n")?;
+    for (key, value) in trailers {
+        write!(writer, "{}: {}\r\n", key, value)?;
+    }
+    writer.write_all(b"\r\n")?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(data: &[u8], max_size: Option<usize>, max_trailer_size: usize) -> Result<(Vec<u8>, Vec<String>), ChunkedError> {
+        let mut body = Vec::new();
+        let trailers = read_chunked_body(&mut &data[..], &mut body, max_size, max_trailer_size)?;
+        Ok((body, trailers))
+    }
+
+    #[test]
+    fn decodes_chunks_and_trailers() {
+        let (body, trailers) = decode(b"5;name=value\r\nhello\r\nA\r\n, world!!!\r\n0\r\nChecksum: 1\r\nX-Done: yes\r\n\r\n", None, 1024).ok().unwrap();
+        assert_eq!(body, b"hello, world!!!");
+        assert_eq!(trailers, vec!["Checksum: 1", "X-Done: yes"]);
+
+        let (body, trailers) = decode(b"0\r\n\r\nleft for the next request", None, 0).ok().unwrap();
+        assert!(body.is_empty() && trailers.is_empty());
+    }
+
+    #[test]
+    fn accepts_bare_line_feeds() {
+        let (body, _) = decode(b"3\nabc\n0\n\n", None, 0).ok().unwrap();
+        assert_eq!(body, b"abc");
+    }
+
+    #[test]
+    fn rejects_invalid_framing() {
+        for data in [&b"x\r\nabc\r\n0\r\n\r\n"[..], b"-3\r\nabc\r\n0\r\n\r\n", b"\r\n", b"3\r\nabcd\r\n0\r\n\r\n", b" \r\n0\r\n\r\n"] {
+            assert!(matches!(decode(data, None, 1024), Err(ChunkedError::Invalid)), "{:?}", String::from_utf8_lossy(data));
+        }
+        let long_extension = format!("1;{}\r\na\r\n0\r\n\r\n", "x".repeat(CHUNK_LINE_LIMIT));
+        assert!(matches!(decode(long_extension.as_bytes(), None, 1024), Err(ChunkedError::Invalid)));
+    }
+
+    #[test]
+    fn rejects_truncated_bodies() {
+        for data in [&b""[..], b"5\r\nhel", b"5\r\nhello\r\n", b"0\r\nTrailer: 1\r\n"] {
+            assert!(matches!(decode(data, None, 1024), Err(ChunkedError::Io(_))), "{:?}", String::from_utf8_lossy(data));
+        }
+    }
+
+    #[test]
+    fn enforces_size_limits() {
+        let data = b"5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n";
+        assert!(decode(data, Some(10), 0).is_ok());
+        assert!(matches!(decode(data, Some(9), 0), Err(ChunkedError::TooLarge)));
+        assert!(matches!(decode(b"FFFFFFFFFFFFFFFFFFFF\r\n", None, 0), Err(ChunkedError::TooLarge)));
+        assert!(matches!(decode(b"FFFFFFFFFFFFFFFF\r\n", Some(1024), 0), Err(ChunkedError::TooLarge)));
+
+        let trailers = b"0\r\nA: 1\r\nB: 2\r\n\r\n";
+        assert!(decode(trailers, None, 8).is_ok());
+        assert!(matches!(decode(trailers, None, 7), Err(ChunkedError::Invalid)));
+    }
+
+    #[test]
+    fn encoded_body_decodes_back() {
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| i as u8).collect();
+        let mut trailers = HeaderMap::new();
+        trailers.append(String::from("Checksum"), String::from("abc"));
+        let mut encoded = Vec::new();
+        write_chunked_body(&mut encoded, &mut &data[..], &trailers).unwrap();
+
+        let (body, trailers) = decode(&encoded, None, 1024).ok().unwrap();
+        assert_eq!(body, data);
+        assert_eq!(trailers, vec!["Checksum: abc"]);
+    }
+}
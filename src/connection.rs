@@ -0,0 +1,255 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read},
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Time given to a request body before `TimedReader` starts checking the minimum transfer rate
+const MIN_RATE_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// How often `Connections::drain()` looks for connections that became idle
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Registry of the open connections of a listening server, used to drain them on shutdown
+pub(crate) struct Connections {
+    next_id: AtomicUsize,
+    active: Mutex<HashMap<usize, TrackedConnection>>,
+}
+
+struct TrackedConnection {
+    stream: TcpStream,
+    idle: bool,
+}
+
+/// Reader over a connection that enforces read timeouts.
+/// Every read fails with `io::ErrorKind::TimedOut` once the deadline has passed, no byte arrived within the
+/// idle timeout, or the data comes in slower than the minimum rate.
+pub(crate) struct TimedReader<'a> {
+    stream: &'a TcpStream,
+    deadline: Option<Instant>,
+    idle_timeout: Option<Duration>,
+    min_rate: Option<MinRate>,
+    socket_timeout: Option<Duration>,
+}
+
+struct MinRate {
+    bytes_per_second: u64,
+    started: Instant,
+    received: u64,
+}
+
+/// The connection a request came from, given by `HttpRequest::connection()`
+///
+/// Example:
+/// ```rust,ignore
+/// server.insert_middleware(|req, res, next| {
+///     let connection = req.connection().clone();
+///     let (req, res) = next.run(req, res)?;
+///     println!("{} #{}/{} {} -> {}", connection.peer_addr(), connection.id(), connection.request_count(), req.uri(), res.status().0);
+///     Ok((req, res))
+/// });
+/// ```
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    id: usize,
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+    request_count: usize,
+    is_tls: bool,
+}
+
+/// Registration of one connection, it is removed from the registry when dropped
+pub(crate) struct ConnectionGuard {
+    id: usize,
+    connections: Arc<Connections>,
+}
+
+impl Connections {
+    pub(crate) fn new() -> Self {
+        Self {
+            next_id: AtomicUsize::new(1),
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Track `stream`. A new connection is busy until it has served a request: its client may have sent one already
+    /// while the connection waits for a worker, and a shutdown must not drop it.
+    pub(crate) fn register(connections: &Arc<Connections>, stream: &TcpStream) -> Option<ConnectionGuard> {
+        let stream = stream.try_clone().ok()?;
+        let id = connections.next_id.fetch_add(1, Ordering::Relaxed);
+        connections.active.lock().unwrap().insert(id, TrackedConnection { stream, idle: false });
+        Some(ConnectionGuard {
+            id,
+            connections: Arc::clone(connections),
+        })
+    }
+
+    /// Number of open connections
+    pub(crate) fn len(&self) -> usize {
+        self.active.lock().unwrap().len()
+    }
+
+    /// Close the keep-alive connections that are waiting for their next request
+    pub(crate) fn close_idle(&self) {
+        for connection in self.active.lock().unwrap().values() {
+            if connection.idle {
+                let _ = connection.stream.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    /// Close the idle connections as soon as they become idle, until every connection is closed or `deadline` has passed.
+    /// The connections still open at the deadline are cut off.
+    pub(crate) fn drain(&self, deadline: Instant) {
+        loop {
+            self.close_idle();
+            if self.len() == 0 || Instant::now() >= deadline {
+                break;
+            }
+            thread::sleep(DRAIN_POLL_INTERVAL);
+        }
+        self.close_all();
+    }
+
+    /// Close every connection, requests still in progress are cut off
+    pub(crate) fn close_all(&self) {
+        for connection in self.active.lock().unwrap().values() {
+            let _ = connection.stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+impl ConnectionGuard {
+    /// Mark whether the connection is waiting for its next request
+    pub(crate) fn set_idle(&self, idle: bool) {
+        if let Some(connection) = self.connections.active.lock().unwrap().get_mut(&self.id) {
+            connection.idle = idle;
+        }
+    }
+}
+
+impl<'a> TimedReader<'a> {
+    pub(crate) fn new(stream: &'a TcpStream) -> Self {
+        Self {
+            stream,
+            deadline: None,
+            idle_timeout: None,
+            min_rate: None,
+            socket_timeout: None,
+        }
+    }
+
+    /// Wait at most `timeout` for data (used between requests)
+    pub(crate) fn wait_for(&mut self, timeout: Option<Duration>) {
+        self.deadline = None;
+        self.idle_timeout = timeout;
+        self.min_rate = None;
+    }
+
+    /// Everything read from now on must arrive within `timeout` (used for the request head)
+    pub(crate) fn read_within(&mut self, timeout: Option<Duration>) {
+        self.deadline = timeout.map(|timeout| Instant::now() + timeout);
+        self.idle_timeout = None;
+        self.min_rate = None;
+    }
+
+    /// Wait at most `idle_timeout` for each piece of data and require an average of `min_rate` bytes per second (used for the request body)
+    pub(crate) fn read_steadily(&mut self, idle_timeout: Option<Duration>, min_rate: Option<u64>) {
+        self.deadline = None;
+        self.idle_timeout = idle_timeout;
+        self.min_rate = min_rate.map(|bytes_per_second| MinRate {
+            bytes_per_second,
+            started: Instant::now(),
+            received: 0,
+        });
+    }
+}
+
+impl Read for TimedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut timeout = self.idle_timeout;
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::from(io::ErrorKind::TimedOut));
+            }
+            timeout = Some(timeout.map_or(remaining, |timeout| timeout.min(remaining)));
+        }
+        if timeout != self.socket_timeout {
+            self.stream.set_read_timeout(timeout)?;
+            self.socket_timeout = timeout;
+        }
+
+        let size = match self.stream.read(buf) {
+            Ok(size) => size,
+            // platforms report an expired socket timeout as either of them
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Err(io::Error::from(io::ErrorKind::TimedOut)),
+            Err(e) => return Err(e),
+        };
+
+        if let Some(min_rate) = &mut self.min_rate {
+            min_rate.received += size as u64;
+            let elapsed = min_rate.started.elapsed();
+            if elapsed > MIN_RATE_GRACE_PERIOD && (min_rate.received as f64) < min_rate.bytes_per_second as f64 * elapsed.as_secs_f64() {
+                return Err(io::Error::from(io::ErrorKind::TimedOut));
+            }
+        }
+
+        Ok(size)
+    }
+}
+
+impl ConnectionGuard {
+    pub(crate) fn id(&self) -> usize {
+        self.id
+    }
+}
+
+impl ConnectionInfo {
+    pub(crate) fn new(id: usize, peer_addr: SocketAddr, local_addr: SocketAddr, request_count: usize) -> Self {
+        Self {
+            id,
+            peer_addr,
+            local_addr,
+            request_count,
+            is_tls: false,
+        }
+    }
+
+    /// Number of the connection, unique while the server is listening
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Address of the client. Behind a reverse proxy, it is the address of the proxy.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// Address of the server the client connected to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Number of the request on its connection, 1 for the first one
+    pub fn request_count(&self) -> usize {
+        self.request_count
+    }
+
+    /// Whether the connection is encrypted with TLS. The server does not support TLS yet, so it is always `false`.
+    pub fn is_tls(&self) -> bool {
+        self.is_tls
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.active.lock().unwrap().remove(&self.id);
+    }
+}
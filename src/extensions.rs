@@ -0,0 +1,101 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt,
+    sync::Arc,
+};
+
+/// Values keyed by their type, for passing data along the handler chain (the authenticated user, a request ID, ...).
+/// Define your own types rather than storing `String` or `u64`, so that middlewares do not overwrite each other's values.
+///
+/// Example:
+/// ```rust,ignore
+/// struct RequestId(u64);
+///
+/// server.insert_handler(|mut req, res| {
+///     req.extensions_mut().insert(RequestId(7));
+///     Ok((req, res))
+/// });
+/// server.insert_handler(|req, res| {
+///     if let Some(RequestId(id)) = req.extensions().get::<RequestId>() {
+///         println!("request {}", id);
+///     }
+///     Ok((req, res))
+/// });
+/// ```
+#[derive(Default)]
+pub struct Extensions {
+    values: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+/// Application state of `HttpServer::insert_state()`: one shared value per type
+#[derive(Default)]
+pub(crate) struct StateMap {
+    values: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self { values: HashMap::new() }
+    }
+
+    /// Insert a value, returns the old value of the same type
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.values
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|old| old.downcast().ok())
+            .map(|old| *old)
+    }
+
+    /// Retrieve the value of type `T`
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.values.get(&TypeId::of::<T>()).and_then(|value| value.downcast_ref())
+    }
+
+    /// Retrieve the value of type `T` to change it
+    pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        self.values.get_mut(&TypeId::of::<T>()).and_then(|value| value.downcast_mut())
+    }
+
+    /// Remove the value of type `T` and return it
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+        self.values
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok())
+            .map(|value| *value)
+    }
+
+    /// Whether there is a value of type `T`
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.values.contains_key(&TypeId::of::<T>())
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+}
+
+impl StateMap {
+    pub(crate) fn insert<T: Send + Sync + 'static>(&mut self, value: T) {
+        self.values.insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    pub(crate) fn get<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.values.get(&TypeId::of::<T>()).and_then(|value| Arc::clone(value).downcast().ok())
+    }
+}
+
+/// The values are not printable, only their number is shown
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions").field("len", &self.values.len()).finish()
+    }
+}
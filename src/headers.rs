@@ -0,0 +1,141 @@
+use std::{fmt, iter, slice};
+
+type Entry = (String, String);
+
+/// HTTP headers with case-insensitive names. Insertion order is kept and a name can have several values.
+///
+/// Example:
+/// ```rust,ignore
+/// let mut headers = HeaderMap::new();
+/// headers.insert("Content-Type", "text/plain");
+/// headers.append("Set-Cookie", "a=1");
+/// headers.append("Set-Cookie", "b=2");
+///
+/// assert_eq!(headers.get("content-type"), Some("text/plain"));
+/// assert_eq!(headers.get_all("set-cookie").collect::<Vec<_>>(), vec!["a=1", "b=2"]);
+/// ```
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct HeaderMap {
+    entries: Vec<Entry>,
+}
+
+/// Iterator over the `(name, value)` pairs of a `HeaderMap`
+pub type Iter<'a> = iter::Map<slice::Iter<'a, Entry>, fn(&'a Entry) -> (&'a str, &'a str)>;
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        Self { entries: Vec::new() }
+    }
+
+    /// Retrieve the first value of `name`
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Retrieve every value of `name` in insertion order
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether `name` has at least one value
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Set `name` to `value`, replacing its old values. The header keeps the position of its first old value.
+    pub fn insert<K: Into<String>, V: Into<String>>(&mut self, name: K, value: V) {
+        let name = name.into();
+        let mut value = Some(value.into());
+        self.entries.retain_mut(|(key, old)| {
+            if !key.eq_ignore_ascii_case(&name) {
+                return true;
+            }
+            match value.take() {
+                Some(value) => {
+                    *key = name.clone();
+                    *old = value;
+                    true
+                }
+                None => false,
+            }
+        });
+        if let Some(value) = value {
+            self.entries.push((name, value));
+        }
+    }
+
+    /// Add a value to `name`, keeping its old values
+    pub fn append<K: Into<String>, V: Into<String>>(&mut self, name: K, value: V) {
+        self.entries.push((name.into(), value.into()));
+    }
+
+    /// Remove every value of `name`, returns the first one
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let mut removed = None;
+        self.entries.retain(|(key, value)| {
+            if key.eq_ignore_ascii_case(name) {
+                removed.get_or_insert_with(|| value.clone());
+                false
+            } else {
+                true
+            }
+        });
+        removed
+    }
+
+    /// Number of values (a name with two values counts twice)
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Iterate over the `(name, value)` pairs in insertion order
+    pub fn iter(&self) -> Iter<'_> {
+        self.entries.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// Iterate over the distinct names in insertion order
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        let mut keys = Vec::<&str>::new();
+        for (key, _) in &self.entries {
+            if !keys.iter().any(|seen| seen.eq_ignore_ascii_case(key)) {
+                keys.push(key);
+            }
+        }
+        keys.into_iter()
+    }
+}
+
+impl fmt::Debug for HeaderMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<'a> IntoIterator for &'a HeaderMap {
+    type Item = (&'a str, &'a str);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for HeaderMap {
+    fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> Self {
+        let mut headers = HeaderMap::new();
+        for (key, value) in iter {
+            headers.append(key, value);
+        }
+        headers
+    }
+}
@@ -1,20 +1,44 @@
 //! SpeedRs provide you a fast, efficient way to construct HTTP Server
 
+// Handler signatures hand `HttpRequest` and `HttpResponse` back in the `Err` variant on purpose
+#![allow(clippy::result_large_err)]
+
 /// More utilities
 pub mod utils;
 
+mod body;
+mod chunked;
+mod connection;
+mod extensions;
+mod headers;
+mod parser;
+mod temp_file;
+
+use body::RequestBody;
+pub use body::BodyReader;
+use extensions::StateMap;
+pub use extensions::Extensions;
+pub use headers::HeaderMap;
+
 use std::{
     collections::HashMap,
-    io::{BufRead, BufReader, Read, Write},
-    net::{TcpListener, TcpStream},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
     panic,
+    path::PathBuf,
     sync::{
+        atomic::{AtomicBool, Ordering},
         mpsc::{self, Receiver, Sender},
         Arc, Mutex, RwLock,
     },
-    thread::{spawn, JoinHandle}, error::Error, vec,
+    thread::{self, spawn, JoinHandle}, error::Error, time::{Duration, Instant},
 };
 
+use connection::{ConnectionGuard, Connections, TimedReader};
+pub use connection::ConnectionInfo;
+use parser::{HeaderFields, ParseError, RequestHead};
+
 // Enums
 
 /// HTTP server run mode
@@ -22,35 +46,78 @@ use std::{
 /// - `MultiThread` - run with a thread pool (`HttpServerThreadPool`)
 ///
 /// Example:
-/// ```rust
-/// let mut server = HttpServer::new(HttpServerMode::SingleThread, "127.0.0.1:3000");
-/// let mut server = HttpServer::new(HttpServerMode::MultiThread(HttpServerThreadPool::new(2)), "127.0.0.1:3000");
+/// ```rust,ignore
+/// let mut server = HttpServer::new(HttpServerMode::SingleThread, "127.0.0.1:3000")?;
+/// let mut server = HttpServer::new(HttpServerMode::MultiThread(HttpServerThreadPool::new(2)), "127.0.0.1:3000")?;
 /// ```
 pub enum HttpServerMode {
     SingleThread,
     MultiThread(HttpServerThreadPool),
 }
 
+/// Errors returned by `HttpServer`
+/// - `Bind` - the server could not listen on the address
+/// - `Accept` - the listener stopped working while accepting connections
+///
+/// Example:
+/// ```rust,ignore
+/// match HttpServer::new(HttpServerMode::SingleThread, "127.0.0.1:3000") {
+///     Ok(server) => { ... }
+///     Err(HttpServerError::Bind(e)) => println!("Port 3000 is not available: {}", e),
+///     Err(e) => println!("{}", e),
+/// }
+/// ```
+#[derive(Debug)]
+pub enum HttpServerError {
+    Bind(io::Error),
+    Accept(io::Error),
+}
+
 // Types
 
 type ExecutorJob = Box<dyn FnOnce() + Send + 'static>;
 
+/// Shortest and longest pause of the accept loop after a transient accept error
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// How long and how much of the unread request is discarded after a rejection before the connection is closed
+const REJECT_LINGER_TIMEOUT: Duration = Duration::from_millis(500);
+const REJECT_LINGER_LIMIT: u64 = 1024 * 1024;
+
+/// Result of a request handler. On error the request and response are handed back with the error,
+/// which is passed to the error handler (`HttpServer::set_error_handler()`).
+pub type HandlerResult = Result<(HttpRequest, HttpResponse), (HttpRequest, HttpResponse, Box<dyn Error>)>;
+
 /// Handle function for HTTP request.
 ///
 /// Example:
-/// ```rust
+/// ```rust,ignore
 /// server.insert_handler(|mut req, mut res| {
 ///     res.set_status(HttpStatusStruct(200, "OK"));
 ///     res.set_body(String::from("value"), String::from("Hello World!"));
 ///     Ok((req, res))
 /// });
 /// ```
-pub type RequestHandleFunc = Box<dyn Fn(HttpRequest, HttpResponse) -> Result<(HttpRequest, HttpResponse), (HttpRequest, HttpResponse, Box<dyn Error>)> + Send + Sync + 'static>;
+pub type RequestHandleFunc = Box<dyn Fn(HttpRequest, HttpResponse) -> HandlerResult + Send + Sync + 'static>;
 
-/// Handle function for HTTP request when Error
+/// Middleware function wrapping the rest of the handler chain. It can run code before and after `next`, or skip it to respond right away.
+///
+/// Example:
+/// ```rust,ignore
+/// server.insert_middleware(|req, res, next| {
+///     let started = Instant::now();
+///     let (req, mut res) = next.run(req, res)?;
+///     res.insert_header(String::from("X-Response-Time"), format!("{}ms", started.elapsed().as_millis()));
+///     Ok((req, res))
+/// });
+/// ```
+pub type MiddlewareFunc = Box<dyn Fn(HttpRequest, HttpResponse, Next) -> HandlerResult + Send + Sync + 'static>;
+
+/// Handle function for HTTP request when Error. The response it returns is sent as is, the remaining handlers are skipped.
 /// 
 /// Example:
-/// ```rust
+/// ```rust,ignore
 /// server.set_error_handler(|req, mut res, err| {
 ///     res.set_status(HttpStatusStruct(500, "Interal Server Error"));
 ///     res.text(format!("Unhandled exception: {:?}", err));
@@ -62,10 +129,21 @@ pub type RequestErrorHandleFunc = Box<dyn Fn(HttpRequest, HttpResponse, Box<dyn
 // Traits
 
 // Declarations
+/// The rest of the handler chain, given to a middleware function.
+pub struct Next<'a> {
+    inner: &'a dyn Fn(HttpRequest, HttpResponse) -> HandlerResult,
+}
+
+/// Element of the server's handler chain
+enum ChainHandler {
+    Handle(RequestHandleFunc),
+    Wrap(MiddlewareFunc),
+}
+
 /// HTTP status structure.
 ///
 /// Example:
-/// ```rust
+/// ```rust,ignore
 /// HttpStatusStruct(200, "OK")
 /// HttpStatusStruct(400, "Not Found")
 /// HttpStatusStruct(500, "This is not a bug. It is a feature.")
@@ -73,29 +151,109 @@ pub type RequestErrorHandleFunc = Box<dyn Fn(HttpRequest, HttpResponse, Box<dyn
 pub struct HttpStatusStruct(pub i32, pub &'static str);
 
 /// Thread pool implementation for multi-thread HTTP server process.
-/// ```rust
+/// ```rust,ignore
 /// HttpServerThreadPool::new(4)    // 4 threads for handling requests
 /// ```
 pub struct HttpServerThreadPool {
+    #[allow(dead_code)]
     size: usize,
     executors: Vec<HttpServerThreadExecutor>,
     sender: Option<Sender<ExecutorJob>>,
 }
 
 struct HttpServerThreadExecutor {
+    #[allow(dead_code)]
     id: usize,
     thread: Option<JoinHandle<()>>,
 }
 
+/// HTTP server configuration.
+///
+/// Example:
+/// ```rust,ignore
+/// let mut config = HttpServerConfig::default();
+/// config.keep_alive_timeout = Some(Duration::from_secs(10));
+/// config.max_requests_per_connection = Some(100);
+/// server.set_config(config);
+/// ```
+#[derive(Clone, Debug)]
+pub struct HttpServerConfig {
+    /// Keep connections open between requests (HTTP/1.1 persistent connections). Default is `true`.
+    pub keep_alive: bool,
+    /// How long an idle connection waits for the next request before it is closed. Default is 5 seconds, `None` waits forever.
+    pub keep_alive_timeout: Option<Duration>,
+    /// Maximum number of requests served over one connection. Default is `None` (unlimited).
+    pub max_requests_per_connection: Option<usize>,
+    /// How long open connections may take to finish their requests after a shutdown was requested. Default is 30 seconds.
+    pub shutdown_timeout: Duration,
+    /// Longest request URI, longer ones are answered with `414 URI Too Long`. Default is 8 KiB.
+    pub max_uri_length: usize,
+    /// Maximum number of request headers, more are answered with `431 Request Header Fields Too Large`. Default is 100.
+    pub max_header_count: usize,
+    /// Maximum total size in bytes of the request header lines, larger ones are answered with `431 Request Header Fields Too Large`. Default is 16 KiB.
+    pub max_header_size: usize,
+    /// Maximum size in bytes of a request body, larger ones are answered with `413 Content Too Large`. Default is 10 MiB, `None` is unlimited.
+    pub max_body_size: Option<usize>,
+    /// Request bodies larger than this many bytes are written to a temporary file in `temp_dir` while they are received,
+    /// instead of memory. Default is 1 MiB, `None` keeps every body in memory.
+    pub max_body_in_memory: Option<usize>,
+    /// Folder of the temporary files of large request bodies. Default is `std::env::temp_dir()`.
+    pub temp_dir: PathBuf,
+    /// Time for receiving the request line and headers, a new connection must also send its first request within it.
+    /// Slower clients are answered with `408 Request Timeout`. Default is 10 seconds, `None` waits forever.
+    pub header_read_timeout: Option<Duration>,
+    /// Longest pause while receiving a request body before answering with `408 Request Timeout`. Default is 30 seconds, `None` waits forever.
+    pub body_read_timeout: Option<Duration>,
+    /// Lowest average rate in bytes per second for receiving a request body, checked after its first second.
+    /// Slower clients are answered with `408 Request Timeout`. Default is `None` (no minimum).
+    pub min_body_rate: Option<u64>,
+    /// Longest pause while sending a response before the connection is dropped. Default is 30 seconds, `None` waits forever.
+    pub write_timeout: Option<Duration>,
+}
+
+/// Handle for stopping a listening `HttpServer`. It can be cloned and moved to other threads or a signal handler.
+///
+/// Example:
+/// ```rust,ignore
+/// let handle = server.shutdown_handle();
+/// ctrlc::set_handler(move || handle.shutdown()).unwrap();
+/// server.listen(|| {
+///     println!("Server is listening at http://127.0.0.1:3000");
+/// })?;
+/// println!("Server stopped");
+/// ```
+#[derive(Clone)]
+pub struct HttpServerShutdownHandle {
+    state: Arc<ShutdownState>,
+}
+
+struct ShutdownState {
+    requested: AtomicBool,
+    local_addr: Option<SocketAddr>,
+    /// Connections of the running `listen()` and its `shutdown_timeout`, the handle drains them itself because the
+    /// single thread mode serves a connection inside `listen()`
+    draining: Mutex<Option<(Arc<Connections>, Duration)>>,
+}
+
+/// Everything a connection needs from the server while it is being served
+struct ServerContext {
+    handlers: Arc<RwLock<Vec<ChainHandler>>>,
+    error_handler: Arc<RwLock<RequestErrorHandleFunc>>,
+    config: HttpServerConfig,
+    shutdown: Arc<ShutdownState>,
+    connections: Arc<Connections>,
+    state: Arc<RwLock<StateMap>>,
+}
+
 /// The almighty HTTP server.
 ///
 /// Guide:
 /// 1. Create the server
-/// ```rust
-/// let mut server = HttpServer::new(HttpServerMode::MultiThread(HttpServerThreadPool::new(2)), "127.0.0.1:3000");
+/// ```rust,ignore
+/// let mut server = HttpServer::new(HttpServerMode::MultiThread(HttpServerThreadPool::new(2)), "127.0.0.1:3000")?;
 /// ```
 /// 2. Insert handlers
-/// ```rust
+/// ```rust,ignore
 /// server.insert_handler(|mut req, mut res| {
 ///     res.set_status(HttpStatusStruct(200, "OK"));
 ///     res.set_body(String::from("value"), String::from("Hello World!"));
@@ -103,30 +261,44 @@ struct HttpServerThreadExecutor {
 /// });
 /// ```
 /// 3. Listen
-/// ```rust
+/// ```rust,ignore
 /// server.listen(|| {
 ///     println!("Server is listening at http://127.0.0.1:3000");
-/// });
+/// })?;
 /// ```
 pub struct HttpServer {
     mode: HttpServerMode,
     listener: TcpListener,
-    handlers: Arc<RwLock<Vec<RequestHandleFunc>>>,
-    error_handler: Arc<RwLock<RequestErrorHandleFunc>>
+    handlers: Arc<RwLock<Vec<ChainHandler>>>,
+    error_handler: Arc<RwLock<RequestErrorHandleFunc>>,
+    config: HttpServerConfig,
+    shutdown: Arc<ShutdownState>,
+    state: Arc<RwLock<StateMap>>,
 }
 
 pub struct HttpRequest {
-    headers: HashMap<String, String>,
-    body: Vec<u8>,
+    headers: HeaderMap,
+    body: RequestBody,
+    trailers: HeaderMap,
     method: String,
     uri: String,
     version: String,
+    route_params: HashMap<String, String>,
+    mount_prefix: String,
+    session: Option<utils::session::Session>,
+    extensions: Extensions,
+    state: Arc<RwLock<StateMap>>,
+    connection: ConnectionInfo,
 }
 
 pub struct HttpResponse {
-    headers: HashMap<String, String>,
+    headers: HeaderMap,
     body: Vec<u8>,
+    stream: Option<Box<dyn Read + Send>>,
+    trailers: HeaderMap,
     status: HttpStatusStruct,
+    ended: bool,
+    extensions: Extensions,
 }
 
 // Implementations
@@ -206,78 +378,282 @@ impl HttpServerThreadExecutor {
     }
 }
 
+impl fmt::Display for HttpServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpServerError::Bind(e) => write!(f, "failed to bind the server: {}", e),
+            HttpServerError::Accept(e) => write!(f, "failed to accept connections: {}", e),
+        }
+    }
+}
+
+impl Error for HttpServerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            HttpServerError::Bind(e) | HttpServerError::Accept(e) => Some(e),
+        }
+    }
+}
+
+impl Default for HttpServerConfig {
+    fn default() -> Self {
+        Self {
+            keep_alive: true,
+            keep_alive_timeout: Some(Duration::from_secs(5)),
+            max_requests_per_connection: None,
+            shutdown_timeout: Duration::from_secs(30),
+            max_uri_length: 8 * 1024,
+            max_header_count: 100,
+            max_header_size: 16 * 1024,
+            max_body_size: Some(10 * 1024 * 1024),
+            max_body_in_memory: Some(1024 * 1024),
+            temp_dir: std::env::temp_dir(),
+            header_read_timeout: Some(Duration::from_secs(10)),
+            body_read_timeout: Some(Duration::from_secs(30)),
+            min_body_rate: None,
+            write_timeout: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+impl HttpServerShutdownHandle {
+    /// Stop accepting connections and let `listen()` return once the open connections are drained
+    /// (or `HttpServerConfig::shutdown_timeout` has passed). Calling it more than once has no effect.
+    pub fn shutdown(&self) {
+        if self.state.requested.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        // the accept loop is blocked in accept(), wake it up with a connection of our own
+        if let Some(mut addr) = self.state.local_addr {
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr.ip() {
+                    IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                });
+            }
+            let _ = TcpStream::connect_timeout(&addr, Duration::from_secs(1));
+        }
+
+        // idle keep-alive connections are closed now, busy ones when they finish or at the deadline
+        if let Some((connections, timeout)) = self.state.draining.lock().unwrap().clone() {
+            let deadline = Instant::now() + timeout;
+            let spawned = thread::Builder::new().name(String::from("shutdown-drain")).spawn(move || connections.drain(deadline));
+            if let Err(e) = spawned {
+                println!("Failed to start draining the connections: {}", e);
+            }
+        }
+    }
+
+    /// Whether a shutdown was requested
+    pub fn is_shutdown(&self) -> bool {
+        self.state.is_requested()
+    }
+}
+
+impl ShutdownState {
+    fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
+
 impl HttpServer {
     /**
-     * This function extract string data from the TCP stream request
+     * This function serves the requests coming from one TCP connection until it is closed
      */
-    fn handle_tcp_stream(stream: TcpStream, request_handles: Arc<RwLock<Vec<RequestHandleFunc>>>, request_error_handle: Arc<RwLock<RequestErrorHandleFunc>>) {
-        // init reader
-        let mut reader = BufReader::new(&stream);
-
-        // read the request headlines
-        let request_headlines: Vec<String> = reader
-            .by_ref()
-            .lines()
-            .map(|line| line.unwrap())
-            .take_while(|line| !line.is_empty())
-            .collect();
-
-        // find content length and content type
-        let content_length = request_headlines
-            .iter()
-            .find_map(|line| {
-                let parts: Vec<_> = line.splitn(2, ':').collect();
-                if parts[0].to_lowercase() == "content-length" {
-                    parts.get(1)?.trim().parse::<usize>().ok()
-                } else {
-                    None
+    fn handle_tcp_stream(stream: TcpStream, connection: ConnectionGuard, context: Arc<ServerContext>) {
+        let config = &context.config;
+
+        if stream.set_write_timeout(config.write_timeout).is_err() {
+            return;
+        }
+        // the client is already gone when its address is not known
+        let (peer_addr, local_addr) = match (stream.peer_addr(), stream.local_addr()) {
+            (Ok(peer_addr), Ok(local_addr)) => (peer_addr, local_addr),
+            _ => return,
+        };
+
+        // init reader, it lives as long as the connection so buffered bytes of the next request are kept
+        let mut reader = BufReader::new(TimedReader::new(&stream));
+        let mut served: usize = 0;
+
+        loop {
+            // a draining server does not wait for more requests
+            if served > 0 && context.shutdown.is_requested() {
+                break;
+            }
+
+            // wait for the next request, an idle keep-alive connection is closed after the timeout or by a shutdown
+            connection.set_idle(served > 0);
+            reader.get_mut().wait_for(if served > 0 { config.keep_alive_timeout } else { config.header_read_timeout });
+            match reader.fill_buf() {
+                Ok(buffer) if !buffer.is_empty() => connection.set_idle(false),
+                _ => break,
+            }
+
+            // the whole request head must arrive in time, trickling clients are cut off
+            reader.get_mut().read_within(config.header_read_timeout);
+            let head = match parser::read_request_head(&mut reader, config) {
+                Ok(Some(head)) => head,
+                Ok(None) | Err(ParseError::Closed) => break,
+                Err(ParseError::TimedOut) => {
+                    HttpServer::reject(&stream, "HTTP/1.1", HttpStatusStruct(408, "Request Timeout"));
+                    break;
                 }
-            })
-            .unwrap_or(0);
-
-        // read the request body
-        let mut body = Vec::<u8>::new();
-        if content_length > 0 {
-            body = vec![0; content_length];
-            reader.by_ref().read_exact(&mut body).unwrap();
+                Err(ParseError::Reject(status)) => {
+                    HttpServer::reject(&stream, "HTTP/1.1", status);
+                    break;
+                }
+            };
+
+            // read the request body, it is framed either by Transfer-Encoding or by Content-Length
+            reader.get_mut().read_steadily(config.body_read_timeout, config.min_body_rate);
+            let (body, trailers) = match parser::read_request_body(&mut reader, &head, config) {
+                Ok(body) => body,
+                Err(ParseError::Closed) => break,
+                Err(ParseError::TimedOut) => {
+                    HttpServer::reject(&stream, &head.version, HttpStatusStruct(408, "Request Timeout"));
+                    break;
+                }
+                Err(ParseError::Reject(status)) => {
+                    HttpServer::reject(&stream, &head.version, status);
+                    break;
+                }
+            };
+            let info = ConnectionInfo::new(connection.id(), peer_addr, local_addr, served + 1);
+            let req = HttpRequest::new(head, body, trailers, Arc::clone(&context.state), info);
+            let res = HttpResponse::new();
+
+            let (req, res) = {
+                let handlers = context.handlers.read().unwrap();
+                let error_handler = context.error_handler.read().unwrap();
+                HttpServer::run_chain(&handlers, &error_handler, req, res)
+            };
+
+            served += 1;
+            let keep_alive = config.keep_alive
+                && !context.shutdown.is_requested()
+                && req.keep_alive()
+                && res.keep_alive()
+                && (!res.is_stream() || req.version() == "HTTP/1.1")
+                && config.max_requests_per_connection.is_none_or(|max| served < max);
+
+            let head = req.method() == "HEAD";
+            if HttpServer::write_response(&stream, req.version(), res, keep_alive, head).is_err() || !keep_alive {
+                break;
+            }
         }
-        let mut req = HttpRequest::new(request_headlines, body);
-        let mut res = HttpResponse::new();
+    }
 
-        for handle in request_handles.read().unwrap().iter() {
-            (req, res) = match handle(req, res) {
-                Ok((req, res)) => (req, res),
-                Err((req, res, e)) => request_error_handle.read().unwrap()(req, res, e)
+    /**
+     * Run the handlers in order until one of them ends the response, a middleware runs the rest of the chain through `Next`
+     */
+    fn run_chain(chain: &[ChainHandler], error_handler: &RequestErrorHandleFunc, mut req: HttpRequest, mut res: HttpResponse) -> (HttpRequest, HttpResponse) {
+        for (index, handler) in chain.iter().enumerate() {
+            if res.is_ended() {
+                break;
             }
+            let result = match handler {
+                ChainHandler::Handle(handle) => handle(req, res),
+                ChainHandler::Wrap(middleware) => {
+                    let rest = &chain[index + 1..];
+                    let next = Next {
+                        inner: &|req, res| Ok(HttpServer::run_chain(rest, error_handler, req, res)),
+                    };
+                    return match middleware(req, res, next) {
+                        Ok((req, res)) => (req, res),
+                        Err((req, res, e)) => HttpServer::handle_error(error_handler, req, res, e),
+                    };
+                }
+            };
+            (req, res) = match result {
+                Ok((req, res)) => (req, res),
+                Err((req, res, e)) => HttpServer::handle_error(error_handler, req, res, e),
+            };
         }
+        (req, res)
+    }
 
-        HttpServer::write_response(stream, req, res);
+    /**
+     * Pass the error of a handler to the error handler, the response it returns is final whether it called `end()` or not
+     */
+    fn handle_error(error_handler: &RequestErrorHandleFunc, req: HttpRequest, res: HttpResponse, e: Box<dyn Error>) -> (HttpRequest, HttpResponse) {
+        let (req, mut res) = error_handler(req, res, e);
+        res.end();
+        (req, res)
     }
 
     /**
-     * Server write the response to client
+     * Answer a request that cannot be processed with an error status, the connection is closed afterward
      */
-    fn write_response(mut stream: TcpStream, req: HttpRequest, mut res: HttpResponse) {
-        // construct response body
-        if !res.headers().contains_key("Content-Type") {
+    fn reject(stream: &TcpStream, version: &str, status: HttpStatusStruct) {
+        let mut res = HttpResponse::new();
+        res.insert_header(String::from("Content-Type"), String::from("text/plain"));
+        res.text(String::from(status.1));
+        res.set_status(status);
+        if HttpServer::write_response(stream, version, res, false, false).is_err() {
+            return;
+        }
+
+        // discard what the client is still sending, closing with unread data would reset the connection before the response is read
+        let _ = stream.shutdown(Shutdown::Write);
+        let _ = stream.set_read_timeout(Some(REJECT_LINGER_TIMEOUT));
+        let _ = io::copy(&mut stream.take(REJECT_LINGER_LIMIT), &mut io::sink());
+    }
+
+    /**
+     * Server write the response to client.
+     * The response to a `HEAD` request (`head`) carries the headers of the matching `GET` response but no content:
+     * `Content-Length` is the length of the body the handler produced, or the value it set when it left the body empty.
+     */
+    fn write_response(mut stream: &TcpStream, version: &str, mut res: HttpResponse, keep_alive: bool, head: bool) -> io::Result<()> {
+        // 1xx and 204 responses never have content (RFC 9110 section 6.4.1)
+        let no_content = matches!(res.status().0, 100..=199 | 204);
+        if no_content {
+            res.body.clear();
+            res.stream = None;
+            res.headers.remove("Content-Type");
+        } else if !res.headers().contains_key("Content-Type") {
             res.insert_header(String::from("Content-Type"), String::from("application/octet-stream"));
         }
+
+        // construct response body
+        let body_stream = res.stream.take();
+        let chunked = body_stream.is_some() && version == "HTTP/1.1";
+        if body_stream.is_none() && !no_content {
+            if !(head && res.body().is_empty() && res.headers().contains_key("Content-Length")) {
+                res.insert_header(
+                    String::from("Content-Length"),
+                    res.body().len().to_string(),
+                );
+            }
+        } else {
+            res.headers.remove("Content-Length");
+        }
+        if chunked {
+            res.insert_header(String::from("Transfer-Encoding"), String::from("chunked"));
+            if !res.trailers.is_empty() {
+                let names: Vec<&str> = res.trailers.keys().collect();
+                let names = names.join(", ");
+                res.insert_header(String::from("Trailer"), names);
+            }
+        }
         res.insert_header(
-            String::from("Content-Length"),
-            String::from(res.body().len().to_string()),
+            String::from("Connection"),
+            String::from(if keep_alive { "keep-alive" } else { "close" }),
         );
 
         // construct response headlines
         let mut response_headlines = Vec::<String>::new();
-        response_headlines.push(String::from(format!(
+        response_headlines.push(format!(
             "{} {} {}",
-            req.version(),
+            version,
             res.status().0,
             res.status().1
-        )));
+        ));
 
         for header in res.headers() {
-            response_headlines.push(String::from(format!("{}: {}", header.0, header.1)));
+            response_headlines.push(format!("{}: {}", header.0, header.1));
         }
 
         // construct response string
@@ -285,68 +661,214 @@ impl HttpServer {
 
         for line in response_headlines {
             response_string.push_str(&line);
-            response_string.push('\n');
+            response_string.push_str("\r\n");
         }
-        response_string.push('\n');
+        response_string.push_str("\r\n");
         let mut response_data = Vec::from(response_string.as_bytes());
-        response_data.append(&mut res.body);
 
         // println!("Response string: {}", &response_string);
 
-        stream.write_all(&response_data).unwrap();
+        if head {
+            return stream.write_all(&response_data);
+        }
+
+        match body_stream {
+            Some(mut body_stream) => {
+                let mut writer = BufWriter::new(stream);
+                writer.write_all(&response_data)?;
+                if chunked {
+                    chunked::write_chunked_body(&mut writer, &mut body_stream, &res.trailers)
+                } else {
+                    io::copy(&mut body_stream, &mut writer)?;
+                    writer.flush()
+                }
+            }
+            None => {
+                response_data.append(&mut res.body);
+                stream.write_all(&response_data)
+            }
+        }
+    }
+
+    /// Create a server listening on `bind_adr`
+    ///
+    /// Example:
+    /// ```rust,ignore
+    /// let mut server = HttpServer::new(HttpServerMode::SingleThread, "127.0.0.1:3000")?;
+    /// ```
+    pub fn new(mode: HttpServerMode, bind_adr: &str) -> Result<Self, HttpServerError> {
+        HttpServer::bind(mode, bind_adr)
     }
 
-    pub fn new(mode: HttpServerMode, bind_adr: &str) -> Self {
-        let listener = TcpListener::bind(bind_adr).unwrap();
+    /// Create a server listening on any address accepted by `TcpListener::bind()`
+    ///
+    /// Example:
+    /// ```rust,ignore
+    /// let mut server = HttpServer::bind(HttpServerMode::SingleThread, ("0.0.0.0", 8080))?;
+    /// ```
+    pub fn bind<A: ToSocketAddrs>(mode: HttpServerMode, addr: A) -> Result<Self, HttpServerError> {
+        let listener = TcpListener::bind(addr).map_err(HttpServerError::Bind)?;
+        let shutdown = ShutdownState {
+            requested: AtomicBool::new(false),
+            local_addr: listener.local_addr().ok(),
+            draining: Mutex::new(None),
+        };
         let default_error_handler = |req: HttpRequest, mut res: HttpResponse, err: Box<dyn Error>| {
+            // invalid request data is the client's fault
+            #[cfg(feature = "serde")]
+            if let Some(e) = err.downcast_ref::<utils::ExtractError>() {
+                res.set_status(e.status());
+                res.insert_header(String::from("Content-Type"), String::from("text/plain"));
+                res.text(e.to_string());
+                res.end();
+                return (req, res);
+            }
+            if let Some(e) = err.downcast_ref::<utils::multipart::MultipartError>() {
+                res.set_status(e.status());
+                res.insert_header(String::from("Content-Type"), String::from("text/plain"));
+                res.text(e.to_string());
+                res.end();
+                return (req, res);
+            }
             res.set_status(HttpStatusStruct(500, "Interal Server Error"));
             res.insert_header(String::from("Content-Type"), String::from("text/plain"));
             res.text(format!("Unhandled exception: {:?}", err));
+            res.end();
             (req, res)
         };
-        Self {
+        Ok(Self {
             mode,
             listener,
-            handlers: Arc::new(RwLock::new(Vec::<RequestHandleFunc>::new())),
-            error_handler: Arc::new(RwLock::new(Box::new(default_error_handler)))
-        }
+            handlers: Arc::new(RwLock::new(Vec::<ChainHandler>::new())),
+            error_handler: Arc::new(RwLock::new(Box::new(default_error_handler))),
+            config: HttpServerConfig::default(),
+            shutdown: Arc::new(shutdown),
+            state: Arc::new(RwLock::new(StateMap::default())),
+        })
     }
 
-    pub fn listen<F>(&self, cb: F) where F: Fn() {
+    /// Accept and serve connections until a shutdown is requested.
+    /// Transient accept errors (e.g. running out of file descriptors) are logged and retried with a backoff,
+    /// `HttpServerError::Accept` is returned when the listener itself is broken.
+    pub fn listen<F>(&self, cb: F) -> Result<(), HttpServerError> where F: Fn() {
+        let context = Arc::new(ServerContext {
+            handlers: Arc::clone(&self.handlers),
+            error_handler: Arc::clone(&self.error_handler),
+            config: self.config.clone(),
+            shutdown: Arc::clone(&self.shutdown),
+            connections: Arc::new(Connections::new()),
+            state: Arc::clone(&self.state),
+        });
+        *self.shutdown.draining.lock().unwrap() = Some((Arc::clone(&context.connections), context.config.shutdown_timeout));
         cb();
-        for stream in self.listener.incoming() {
-            let stream = stream.unwrap();
-            let handles_arc = Arc::clone(&self.handlers);
-            let error_handle_arc = Arc::clone(&self.error_handler);
+        let mut accept_backoff = ACCEPT_BACKOFF_MIN;
+        let result = loop {
+            if self.shutdown.is_requested() {
+                break Ok(());
+            }
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                // the client gave up before it was accepted
+                Err(e) if matches!(e.kind(), io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset | io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock) => continue,
+                Err(e) if e.kind() == io::ErrorKind::InvalidInput => break Err(HttpServerError::Accept(e)),
+                Err(e) => {
+                    println!("Failed to accept a connection: {}. Retrying in {:?}", e, accept_backoff);
+                    thread::sleep(accept_backoff);
+                    accept_backoff = (accept_backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                    continue;
+                }
+            };
+            accept_backoff = ACCEPT_BACKOFF_MIN;
+            if self.shutdown.is_requested() {
+                break Ok(());
+            }
+            // registered before it waits in the thread pool queue, so that a shutdown sees it
+            let connection = match Connections::register(&context.connections, &stream) {
+                Some(connection) => connection,
+                None => continue,
+            };
+            let context_arc = Arc::clone(&context);
             match &self.mode {
                 HttpServerMode::SingleThread => {
-                    if let Err(e) = panic::catch_unwind(move || HttpServer::handle_tcp_stream(stream, handles_arc, error_handle_arc)) {
+                    if let Err(e) = panic::catch_unwind(move || HttpServer::handle_tcp_stream(stream, connection, context_arc)) {
                         println!("Panic occurred in handle_tcp_stream()!");
                         println!("Error: {:?}", e);
                     }
                 }
                 HttpServerMode::MultiThread(pool) => {
                     pool.execute(move || {
-                        if let Err(e) = panic::catch_unwind(move || HttpServer::handle_tcp_stream(stream, handles_arc, error_handle_arc)) {
+                        if let Err(e) = panic::catch_unwind(move || HttpServer::handle_tcp_stream(stream, connection, context_arc)) {
                             println!("Panic occurred in handle_tcp_stream()!");
                             println!("Error: {:?}", e);
                         }
                     });
                 }
             }
+        };
+
+        // drain the open connections, idle ones are closed right away and busy ones get until the deadline
+        context.connections.drain(Instant::now() + context.config.shutdown_timeout);
+        *self.shutdown.draining.lock().unwrap() = None;
+        result
+    }
+
+    /// Retrieve a handle which stops the server from another thread
+    ///
+    /// Example:
+    /// ```rust,ignore
+    /// let handle = server.shutdown_handle();
+    /// thread::spawn(move || {
+    ///     thread::sleep(Duration::from_secs(60));
+    ///     handle.shutdown();
+    /// });
+    /// ```
+    pub fn shutdown_handle(&self) -> HttpServerShutdownHandle {
+        HttpServerShutdownHandle {
+            state: Arc::clone(&self.shutdown),
         }
     }
 
+    /// Insert a request handler at the end of the chain. Handlers run in insertion order until one of them ends the response (`HttpResponse::end()`).
+    ///
+    /// Example:
+    /// ```rust,ignore
+    /// server.insert_handler(|req, mut res| {
+    ///     if req.headers().get("Authorization").is_none() {
+    ///         res.set_status(HttpStatusStruct(401, "Unauthorized"));
+    ///         res.end();     // the handlers inserted after this one are skipped
+    ///     }
+    ///     Ok((req, res))
+    /// });
+    /// ```
     pub fn insert_handler<F>(&mut self, handler: F)
-                where F: Fn(HttpRequest, HttpResponse) -> Result<(HttpRequest, HttpResponse), (HttpRequest, HttpResponse, Box<dyn Error>)> + Send + Sync + 'static {
+                where F: Fn(HttpRequest, HttpResponse) -> HandlerResult + Send + Sync + 'static {
         let mut writter = self.handlers.write().unwrap();
-        writter.push(Box::new(handler));
+        writter.push(ChainHandler::Handle(Box::new(handler)));
     }
 
-    /// Custom error handling function
+    /// Insert a middleware at the end of the chain, it wraps the handlers inserted after it.
+    /// `next.run(req, res)` runs them, code before and after it runs before and after them.
+    ///
+    /// Example:
+    /// ```rust,ignore
+    /// server.insert_middleware(|req, res, next| {
+    ///     let method = req.method().clone();
+    ///     let uri = req.uri().clone();
+    ///     let (req, res) = next.run(req, res)?;
+    ///     println!("{} {} -> {}", method, uri, res.status().0);
+    ///     Ok((req, res))
+    /// });
+    /// ```
+    pub fn insert_middleware<F>(&mut self, middleware: F)
+                where F: Fn(HttpRequest, HttpResponse, Next) -> HandlerResult + Send + Sync + 'static {
+        let mut writter = self.handlers.write().unwrap();
+        writter.push(ChainHandler::Wrap(Box::new(middleware)));
+    }
+
+    /// Custom error handling function. The response it returns is sent as is, the remaining handlers are skipped.
     /// 
     /// Example:
-    /// ```rust
+    /// ```rust,ignore
     /// server.set_error_handler(|req, mut res, err| {
     ///     res.set_status(HttpStatusStruct(500, "Interal Server Error"));
     ///     res.text(format!("Unhandled exception: {:?}", err));
@@ -358,43 +880,109 @@ impl HttpServer {
         let mut writter = self.error_handler.write().unwrap();
         *writter = Box::new(handler);
     }
+
+    /// Register a value shared by every request, handlers get it with `HttpRequest::state()`.
+    /// There is one value per type, inserting another value of the same type replaces it.
+    ///
+    /// Example:
+    /// ```rust,ignore
+    /// struct AppConfig {
+    ///     greeting: String,
+    /// }
+    ///
+    /// server.insert_state(AppConfig { greeting: String::from("Hello") });
+    /// server.insert_handler(|req, mut res| {
+    ///     let config = req.state::<AppConfig>().unwrap();
+    ///     res.set_status(HttpStatusStruct(200, "OK"));
+    ///     res.text(config.greeting.clone());
+    ///     Ok((req, res))
+    /// });
+    /// ```
+    pub fn insert_state<T: Send + Sync + 'static>(&mut self, value: T) {
+        let mut writter = self.state.write().unwrap();
+        writter.insert(value);
+    }
+
+    /// Set the server configuration, it takes effect on the next call of `listen()`
+    ///
+    /// Example:
+    /// ```rust,ignore
+    /// let mut config = HttpServerConfig::default();
+    /// config.keep_alive = false;
+    /// server.set_config(config);
+    /// ```
+    pub fn set_config(&mut self, config: HttpServerConfig) {
+        self.config = config;
+    }
+
+    /// Retrieve the server configuration
+    pub fn config(&self) -> &HttpServerConfig {
+        &self.config
+    }
 }
 
-impl HttpRequest {
-    fn new(mut request_headlines: Vec<String>, body: Vec<u8>) -> Self {
-        // get the first line out
-        let first_line = request_headlines.remove(0);
-        let metadata: Vec<&str> = first_line.split(" ").collect();
-        let method = String::from(metadata[0]);
-        let uri = String::from(metadata[1]);
-        let version = String::from(metadata[2]);
-
-        // transform header strings to headers map
-        let mut headers = HashMap::<String, String>::new();
-        for line in request_headlines {
-            let elements: Vec<&str> = line.split(": ").collect();
-            if elements.len() >= 2 {
-                headers.insert(String::from(elements[0]), String::from(elements[1]));
-            }
-        }
+impl Next<'_> {
+    /// Run the rest of the handler chain
+    pub fn run(self, req: HttpRequest, res: HttpResponse) -> HandlerResult {
+        (self.inner)(req, res)
+    }
+}
 
+impl HttpRequest {
+    fn new(head: RequestHead, body: RequestBody, trailers: HeaderFields, state: Arc<RwLock<StateMap>>, connection: ConnectionInfo) -> Self {
         Self {
-            headers,
+            headers: head.headers.into_iter().collect(),
             body,
-            method,
-            uri,
-            version,
+            trailers: trailers.into_iter().collect(),
+            method: head.method,
+            uri: head.uri,
+            version: head.version,
+            route_params: HashMap::new(),
+            mount_prefix: String::new(),
+            session: None,
+            extensions: Extensions::new(),
+            state,
+            connection,
         }
     }
 
     /// Retrieve the request headers
-    pub fn headers(&self) -> &HashMap<String, String> {
+    pub fn headers(&self) -> &HeaderMap {
         &self.headers
     }
 
-    /// Retrieve the request body
+    /// Retrieve the request body. A body larger than `HttpServerConfig::max_body_in_memory` is loaded from its temporary
+    /// file on the first call, use `body_reader()` to read it without holding it in memory.
     pub fn body(&self) -> &Vec<u8> {
-        &self.body
+        self.body.bytes()
+    }
+
+    /// Read the request body as a stream, a large body is read from its temporary file instead of being loaded in memory
+    ///
+    /// Example:
+    /// ```rust,ignore
+    /// server.insert_handler(|req, res| {
+    ///     let mut reader = match req.body_reader() {
+    ///         Ok(reader) => reader,
+    ///         Err(e) => return Err((req, res, Box::new(e))),
+    ///     };
+    ///     let mut file = File::create("upload.bin").unwrap();
+    ///     io::copy(&mut reader, &mut file).unwrap();
+    ///     Ok((req, res))
+    /// });
+    /// ```
+    pub fn body_reader(&self) -> io::Result<BodyReader<'_>> {
+        self.body.reader()
+    }
+
+    /// Size in bytes of the request body, without loading it in memory
+    pub fn body_size(&self) -> u64 {
+        self.body.size()
+    }
+
+    /// Retrieve the trailer fields sent after a chunked request body
+    pub fn trailers(&self) -> &HeaderMap {
+        &self.trailers
     }
 
     /// Retrieve the request method
@@ -407,21 +995,63 @@ impl HttpRequest {
         &self.uri
     }
 
+    /// Retrieve the values attached to the request by the handlers before
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// Retrieve the values attached to the request, to pass a value to the handlers after
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    /// Retrieve the connection the request came from: the client address, the connection ID, ...
+    pub fn connection(&self) -> &ConnectionInfo {
+        &self.connection
+    }
+
+    /// Retrieve the application state of type `T` registered with `HttpServer::insert_state()`
+    ///
+    /// Example:
+    /// ```rust,ignore
+    /// let config = req.state::<AppConfig>().unwrap();
+    /// ```
+    pub fn state<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.state.read().unwrap().get::<T>()
+    }
+
     /// Retrieve the HTTP version
     pub fn version(&self) -> &String {
         &self.version
     }
+
+    /// Whether the client wants to keep the connection open after this request.
+    /// HTTP/1.1 keeps it open unless `Connection: close` is sent, HTTP/1.0 closes it unless `Connection: keep-alive` is sent.
+    fn keep_alive(&self) -> bool {
+        let connection: Vec<&str> = self.headers.get_all("Connection").collect();
+        if connection.iter().any(|value| has_token(value, "close")) {
+            false
+        } else if connection.iter().any(|value| has_token(value, "keep-alive")) {
+            true
+        } else {
+            self.version == "HTTP/1.1"
+        }
+    }
 }
 
 impl HttpResponse {
     fn new() -> Self {
-        let headers = HashMap::<String, String>::new();
+        let headers = HeaderMap::new();
         let status = HttpStatusStruct(404, "Not Found");
 
         Self {
             headers,
             body: Vec::new(),
+            stream: None,
+            trailers: HeaderMap::new(),
             status,
+            ended: false,
+            extensions: Extensions::new(),
         }
     }
 
@@ -430,12 +1060,32 @@ impl HttpResponse {
         self.headers.insert(key, value);
     }
 
+    /// Append a pair key - value to response headers, old values of key are kept (e.g. several `Set-Cookie`)
+    pub fn append_header(&mut self, key: String, value: String) {
+        self.headers.append(key, value);
+    }
+
     /// Retrieve the response headers
-    pub fn headers(&self) -> &HashMap<String, String> {
+    pub fn headers(&self) -> &HeaderMap {
         &self.headers
     }
 
-    /// Retrieve the response body
+    /// Retrieve the response headers for modification
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.headers
+    }
+
+    /// Retrieve the values attached to the response, e.g. for a middleware to read after `next.run()`. They are not sent.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// Retrieve the values attached to the response for modification
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    /// Retrieve the response body (empty when the body is streamed)
     pub fn body(&self) -> &[u8] {
         &self.body
     }
@@ -448,10 +1098,72 @@ impl HttpResponse {
     /// Set the response body text
     pub fn text(&mut self, t: String) {
         self.body = Vec::from(t.as_bytes());
+        self.stream = None;
     }
 
     pub fn bytes(&mut self, b: Vec<u8>) {
         self.body = b;
+        self.stream = None;
+    }
+
+    /// Set the response body to `value` serialized as JSON and `Content-Type` to `application/json; charset=utf-8` (requires the `json` feature).
+    /// The response is not changed when `value` cannot be serialized.
+    ///
+    /// Example:
+    /// ```rust,ignore
+    /// server.insert_handler(|req, mut res| {
+    ///     res.set_status(HttpStatusStruct(200, "OK"));
+    ///     if let Err(e) = res.json(&vec!["a", "b"]) {
+    ///         return Err((req, res, Box::new(e)));
+    ///     }
+    ///     Ok((req, res))
+    /// });
+    /// ```
+    #[cfg(feature = "json")]
+    pub fn json<T: serde::Serialize + ?Sized>(&mut self, value: &T) -> Result<(), serde_json::Error> {
+        let body = serde_json::to_vec(value)?;
+        self.insert_header(String::from("Content-Type"), String::from("application/json; charset=utf-8"));
+        self.bytes(body);
+        Ok(())
+    }
+
+    /// Add a `Set-Cookie` header, the cookies added before are kept
+    ///
+    /// Example:
+    /// ```rust,ignore
+    /// res.add_cookie(Cookie::new("session", &id).path("/").http_only(true).same_site(SameSite::Lax));
+    /// ```
+    pub fn add_cookie(&mut self, cookie: utils::cookie::Cookie) {
+        self.headers.append("Set-Cookie", cookie.to_string());
+    }
+
+    /// Stream the response body from `reader` instead of buffering it in memory.
+    /// The body is sent with `Transfer-Encoding: chunked` to HTTP/1.1 clients, HTTP/1.0 clients receive it until the connection is closed.
+    ///
+    /// Example:
+    /// ```rust,ignore
+    /// let file = File::open("exports/report.csv").unwrap();
+    /// res.insert_header(String::from("Content-Type"), String::from("text/csv"));
+    /// res.stream(BufReader::new(file));
+    /// ```
+    pub fn stream<R>(&mut self, reader: R) where R: Read + Send + 'static {
+        self.body = Vec::new();
+        self.stream = Some(Box::new(reader));
+    }
+
+    /// Whether the response body is streamed
+    pub fn is_stream(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Insert a trailer field sent after a streamed body (if key is already existed, replace the old value of key)
+    pub fn insert_trailer(&mut self, key: String, value: String) {
+        self.trailers.insert(key, value);
+    }
+
+    /// Retrieve the response trailers
+    pub fn trailers(&self) -> &HeaderMap {
+        &self.trailers
     }
 
     /// Retrieve the response status
@@ -463,4 +1175,26 @@ impl HttpResponse {
     pub fn set_status(&mut self, status: HttpStatusStruct) {
         self.status = status;
     }
+
+    /// Finish the response, the remaining handlers in the chain are skipped and the response is sent
+    pub fn end(&mut self) {
+        self.ended = true;
+    }
+
+    /// Whether the response was finished by `end()`
+    pub fn is_ended(&self) -> bool {
+        self.ended
+    }
+
+    /// Whether the handlers allow the connection to stay open, a handler can send `Connection: close` to close it
+    fn keep_alive(&self) -> bool {
+        !self.headers.get_all("Connection").any(|value| has_token(value, "close"))
+    }
+}
+
+// Functions
+
+/// Check whether a comma separated header value contains `token` (case-insensitive)
+fn has_token(value: &str, token: &str) -> bool {
+    value.split(',').any(|part| part.trim().eq_ignore_ascii_case(token))
 }
@@ -0,0 +1,494 @@
+use std::io::{self, BufRead, Write};
+
+use crate::{body::{BodyWriter, RequestBody}, chunked::{self, ChunkedError}, HttpServerConfig, HttpStatusStruct};
+
+/// Longest request method and HTTP version accepted on the request line
+const MAX_METHOD_LENGTH: usize = 32;
+const MAX_VERSION_LENGTH: usize = 16;
+
+/// Header fields in the order they were received
+pub(crate) type HeaderFields = Vec<(String, String)>;
+
+/// Request line and header section of a request
+pub(crate) struct RequestHead {
+    pub(crate) method: String,
+    pub(crate) uri: String,
+    pub(crate) version: String,
+    pub(crate) headers: HeaderFields,
+}
+
+/// Why a request could not be read
+pub(crate) enum ParseError {
+    /// The connection failed or was closed in the middle of the request
+    Closed,
+    /// The client was too slow to send the request
+    TimedOut,
+    /// The request is invalid and is answered with this status before the connection is closed
+    Reject(HttpStatusStruct),
+}
+
+/// Why a line could not be read by `read_line_limited()`
+pub(crate) enum LineError {
+    Io(io::Error),
+    TooLong,
+}
+
+/// Why `copy_exact()` could not move the bytes
+pub(crate) enum CopyError {
+    /// Reading from the connection failed or it was closed
+    Read(io::Error),
+    /// Storing the bytes failed
+    Write(io::Error),
+}
+
+impl From<io::Error> for ParseError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => ParseError::TimedOut,
+            _ => ParseError::Closed,
+        }
+    }
+}
+
+impl From<io::Error> for LineError {
+    fn from(e: io::Error) -> Self {
+        LineError::Io(e)
+    }
+}
+
+impl RequestHead {
+    /// All values of a header (case-insensitive name)
+    fn header_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Read and validate the request line and the header section.
+/// Returns `None` when the connection is closed before a request starts.
+pub(crate) fn read_request_head<R: BufRead>(reader: &mut R, config: &HttpServerConfig) -> Result<Option<RequestHead>, ParseError> {
+    // the request line, empty lines before it are ignored (RFC 9112 section 2.2)
+    let request_line_limit = MAX_METHOD_LENGTH + config.max_uri_length + MAX_VERSION_LENGTH + 2;
+    let request_line = loop {
+        match read_line_limited(reader, request_line_limit) {
+            Ok(Some(line)) if line.is_empty() => continue,
+            Ok(Some(line)) => break line,
+            Ok(None) => return Ok(None),
+            Err(LineError::TooLong) => return Err(reject(414, "URI Too Long")),
+            Err(LineError::Io(e)) => return Err(ParseError::from(e)),
+        }
+    };
+    let (method, uri, version) = parse_request_line(request_line, config)?;
+
+    // the header section ends with an empty line
+    let mut headers = HeaderFields::new();
+    let mut header_size: usize = 0;
+    loop {
+        let limit = config.max_header_size.saturating_sub(header_size);
+        let line = match read_line_limited(reader, limit) {
+            Ok(Some(line)) => line,
+            Ok(None) => return Err(ParseError::Closed),
+            Err(LineError::TooLong) => return Err(reject(431, "Request Header Fields Too Large")),
+            Err(LineError::Io(e)) => return Err(ParseError::from(e)),
+        };
+        if line.is_empty() {
+            break;
+        }
+        header_size += line.len();
+        if headers.len() >= config.max_header_count {
+            return Err(reject(431, "Request Header Fields Too Large"));
+        }
+        headers.push(parse_header_line(&line).ok_or_else(|| reject(400, "Bad Request"))?);
+    }
+
+    let head = RequestHead {
+        method,
+        uri,
+        version,
+        headers,
+    };
+
+    // HTTP/1.1 requests must identify the host (RFC 9112 section 3.2)
+    if head.version == "HTTP/1.1" && head.header_values("Host").count() != 1 {
+        return Err(reject(400, "Bad Request"));
+    }
+
+    Ok(Some(head))
+}
+
+/// Read the request body framed by `Transfer-Encoding` or `Content-Length`, returns the body and the trailer fields.
+/// Bodies larger than `HttpServerConfig::max_body_in_memory` are written to a temporary file.
+pub(crate) fn read_request_body<R: BufRead>(reader: &mut R, head: &RequestHead, config: &HttpServerConfig) -> Result<(RequestBody, HeaderFields), ParseError> {
+    let transfer_encoding: Vec<&str> = head
+        .header_values("Transfer-Encoding")
+        .flat_map(|value| value.split(','))
+        .map(|coding| coding.trim())
+        .filter(|coding| !coding.is_empty())
+        .collect();
+    let content_length: Vec<&str> = head
+        .header_values("Content-Length")
+        .flat_map(|value| value.split(','))
+        .map(|value| value.trim())
+        .collect();
+
+    if !transfer_encoding.is_empty() {
+        // a request carrying both headers may be an attempt of request smuggling (RFC 9112 section 6.1)
+        if !content_length.is_empty() {
+            return Err(reject(400, "Bad Request"));
+        }
+
+        // chunked must be the final coding, other codings are not supported
+        if !transfer_encoding.last().is_some_and(|coding| coding.eq_ignore_ascii_case("chunked")) {
+            return Err(reject(400, "Bad Request"));
+        }
+        if transfer_encoding.len() > 1 {
+            return Err(reject(501, "Not Implemented"));
+        }
+
+        let mut body = BodyWriter::new(config.max_body_in_memory, &config.temp_dir);
+        return match chunked::read_chunked_body(reader, &mut body, config.max_body_size, config.max_header_size) {
+            Ok(trailers) => {
+                let mut fields = HeaderFields::new();
+                for line in trailers {
+                    if fields.len() >= config.max_header_count {
+                        return Err(reject(431, "Request Header Fields Too Large"));
+                    }
+                    fields.push(parse_header_line(&line).ok_or_else(|| reject(400, "Bad Request"))?);
+                }
+                Ok((finish_body(body)?, fields))
+            }
+            Err(ChunkedError::Io(e)) => Err(ParseError::from(e)),
+            Err(ChunkedError::Write(e)) => Err(storage_failed(e)),
+            Err(ChunkedError::Invalid) => Err(reject(400, "Bad Request")),
+            Err(ChunkedError::TooLarge) => Err(reject(413, "Content Too Large")),
+        };
+    }
+
+    // repeated Content-Length values must all agree (RFC 9110 section 8.6)
+    let mut length: Option<usize> = None;
+    for value in content_length {
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(reject(400, "Bad Request"));
+        }
+        let value = match value.parse::<usize>() {
+            Ok(value) => value,
+            Err(_) => return Err(reject(413, "Content Too Large")),
+        };
+        if length.is_some_and(|length| length != value) {
+            return Err(reject(400, "Bad Request"));
+        }
+        length = Some(value);
+    }
+
+    let length = length.unwrap_or(0);
+    if config.max_body_size.is_some_and(|max| length > max) {
+        return Err(reject(413, "Content Too Large"));
+    }
+    let mut body = BodyWriter::new(config.max_body_in_memory, &config.temp_dir);
+    match copy_exact(reader, &mut body, length) {
+        Ok(()) => Ok((finish_body(body)?, Vec::new())),
+        Err(CopyError::Read(e)) => Err(ParseError::from(e)),
+        Err(CopyError::Write(e)) => Err(storage_failed(e)),
+    }
+}
+
+/// Move exactly `len` bytes from `reader` to `writer`, an early end of stream is a read error
+pub(crate) fn copy_exact<R: BufRead, W: Write>(reader: &mut R, writer: &mut W, mut len: usize) -> Result<(), CopyError> {
+    while len > 0 {
+        let available = match reader.fill_buf() {
+            Ok([]) => return Err(CopyError::Read(io::Error::from(io::ErrorKind::UnexpectedEof))),
+            Ok(available) => available,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(CopyError::Read(e)),
+        };
+        let size = available.len().min(len);
+        writer.write_all(&available[..size]).map_err(CopyError::Write)?;
+        reader.consume(size);
+        len -= size;
+    }
+    Ok(())
+}
+
+fn finish_body(body: BodyWriter) -> Result<RequestBody, ParseError> {
+    body.finish().map_err(storage_failed)
+}
+
+/** A body that cannot be stored is the server's fault */
+fn storage_failed(e: io::Error) -> ParseError {
+    println!("Failed to store a request body: {}", e);
+    reject(500, "Internal Server Error")
+}
+
+/// Split a `Name: value` line, the name must be a token directly followed by the colon
+pub(crate) fn parse_header_line(line: &str) -> Option<(String, String)> {
+    let (name, value) = line.split_once(':')?;
+    if name.is_empty() || !name.bytes().all(is_tchar) {
+        return None;
+    }
+    Some((String::from(name), String::from(value.trim_matches([' ', '\t']))))
+}
+
+/// Read one line of at most `limit` bytes without its line ending.
+/// Returns `None` when the stream ends before the line starts.
+pub(crate) fn read_line_limited<R: BufRead>(reader: &mut R, limit: usize) -> Result<Option<String>, LineError> {
+    let mut line = Vec::<u8>::new();
+    loop {
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(LineError::Io(e)),
+        };
+        if available.is_empty() {
+            if line.is_empty() {
+                return Ok(None);
+            }
+            return Err(LineError::Io(io::Error::from(io::ErrorKind::UnexpectedEof)));
+        }
+
+        match available.iter().position(|b| *b == b'\n') {
+            Some(index) => {
+                line.extend_from_slice(&available[..index]);
+                reader.consume(index + 1);
+                break;
+            }
+            None => {
+                let size = available.len();
+                line.extend_from_slice(available);
+                reader.consume(size);
+            }
+        }
+
+        // one more byte is allowed for the CR of the line ending
+        if line.len() > limit + 1 {
+            return Err(LineError::TooLong);
+        }
+    }
+
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    if line.len() > limit {
+        return Err(LineError::TooLong);
+    }
+    Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+}
+
+/// Split and validate `method SP request-target SP HTTP-version`
+fn parse_request_line(line: String, config: &HttpServerConfig) -> Result<(String, String, String), ParseError> {
+    let parts: Vec<&str> = line.split(' ').collect();
+    if parts.len() != 3 {
+        return Err(reject(400, "Bad Request"));
+    }
+    let (method, uri, version) = (parts[0], parts[1], parts[2]);
+
+    if method.is_empty() || method.len() > MAX_METHOD_LENGTH || !method.bytes().all(is_tchar) {
+        return Err(reject(400, "Bad Request"));
+    }
+    if uri.len() > config.max_uri_length {
+        return Err(reject(414, "URI Too Long"));
+    }
+    if uri.is_empty() || !uri.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(reject(400, "Bad Request"));
+    }
+
+    // HTTP-version = "HTTP/" DIGIT "." DIGIT
+    let digits = version.strip_prefix("HTTP/").map(|digits| digits.as_bytes());
+    match digits {
+        Some([major, b'.', minor]) if major.is_ascii_digit() && minor.is_ascii_digit() => {
+            if version != "HTTP/1.0" && version != "HTTP/1.1" {
+                return Err(reject(505, "HTTP Version Not Supported"));
+            }
+        }
+        _ => return Err(reject(400, "Bad Request")),
+    }
+
+    Ok((String::from(method), String::from(uri), String::from(version)))
+}
+
+/// Characters allowed in a token such as a method or a header name (RFC 9110 section 5.6.2)
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+fn reject(code: i32, reason: &'static str) -> ParseError {
+    ParseError::Reject(HttpStatusStruct(code, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> HttpServerConfig {
+        HttpServerConfig {
+            max_uri_length: 32,
+            max_header_count: 4,
+            max_header_size: 64,
+            max_body_size: Some(16),
+            ..HttpServerConfig::default()
+        }
+    }
+
+    fn head(data: &str) -> Result<Option<RequestHead>, ParseError> {
+        read_request_head(&mut data.as_bytes(), &config())
+    }
+
+    /// Status of the rejection of a request, `None` when it was accepted
+    fn rejected<T>(result: Result<T, ParseError>) -> Option<i32> {
+        match result {
+            Ok(_) => None,
+            Err(ParseError::Reject(status)) => Some(status.0),
+            Err(ParseError::Closed) => panic!("connection closed"),
+            Err(ParseError::TimedOut) => panic!("timed out"),
+        }
+    }
+
+    fn stored_body(headers: &str, data: &str, config: &HttpServerConfig) -> Result<(RequestBody, HeaderFields), ParseError> {
+        let request = format!("POST / HTTP/1.1\r\nHost: a\r\n{}\r\n{}", headers, data);
+        let mut reader = request.as_bytes();
+        let head = read_request_head(&mut reader, config).ok().flatten().unwrap();
+        read_request_body(&mut reader, &head, config)
+    }
+
+    fn body(headers: &str, data: &str) -> Result<(Vec<u8>, HeaderFields), ParseError> {
+        stored_body(headers, data, &config()).map(|(body, trailers)| (body.bytes().clone(), trailers))
+    }
+
+    #[test]
+    fn reads_request_head() {
+        let parsed = head("\r\nGET /a?b=c HTTP/1.1\r\nHost: example.com\r\nX-Empty:\r\nX-Padded: \t value \t\r\n\r\n").ok().flatten().unwrap();
+        assert_eq!((parsed.method.as_str(), parsed.uri.as_str(), parsed.version.as_str()), ("GET", "/a?b=c", "HTTP/1.1"));
+        assert_eq!(parsed.headers, vec![
+            (String::from("Host"), String::from("example.com")),
+            (String::from("X-Empty"), String::new()),
+            (String::from("X-Padded"), String::from("value")),
+        ]);
+        assert!(head("GET / HTTP/1.0\n\n").ok().flatten().is_some());
+    }
+
+    #[test]
+    fn closed_connections() {
+        assert!(matches!(head(""), Ok(None)));
+        assert!(matches!(head("\r\n"), Ok(None)));
+        assert!(matches!(head("GET / HTTP/1.1\r\nHost: a\r\n"), Err(ParseError::Closed)));
+        assert!(matches!(head("GET / HT"), Err(ParseError::Closed)));
+    }
+
+    #[test]
+    fn rejects_malformed_request_lines() {
+        for line in ["GET /", "GET  / HTTP/1.1", "GET / HTTP/1.1 x", "G@T / HTTP/1.1", "GET /\u{7f} HTTP/1.1", "GET / HTTP/11", "GET / http/1.1"] {
+            assert_eq!(rejected(head(&format!("{}\r\nHost: a\r\n\r\n", line))), Some(400), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_versions() {
+        assert_eq!(rejected(head("GET / HTTP/2.0\r\nHost: a\r\n\r\n")), Some(505));
+        assert_eq!(rejected(head("GET / HTTP/0.9\r\n\r\n")), Some(505));
+    }
+
+    #[test]
+    fn rejects_long_uris() {
+        let uri = format!("/{}", "a".repeat(31));
+        assert_eq!(rejected(head(&format!("GET {} HTTP/1.1\r\nHost: a\r\n\r\n", uri))), None);
+        assert_eq!(rejected(head(&format!("GET {}a HTTP/1.1\r\nHost: a\r\n\r\n", uri))), Some(414));
+        // longer than the whole request line
+        assert_eq!(rejected(head(&format!("GET {} HTTP/1.1\r\nHost: a\r\n\r\n", "a".repeat(100)))), Some(414));
+    }
+
+    #[test]
+    fn rejects_large_header_sections() {
+        let five = "Host: a\r\nB: 1\r\nC: 1\r\nD: 1\r\nE: 1\r\n";
+        assert_eq!(rejected(head(&format!("GET / HTTP/1.1\r\n{}\r\n", &five[..five.len() - 6]))), None);
+        assert_eq!(rejected(head(&format!("GET / HTTP/1.1\r\n{}\r\n", five))), Some(431));
+
+        // "Host: a" and "X: ..." are 64 bytes without their line endings
+        let value = "v".repeat(54);
+        assert_eq!(rejected(head(&format!("GET / HTTP/1.1\r\nHost: a\r\nX: {}\r\n\r\n", value))), None);
+        assert_eq!(rejected(head(&format!("GET / HTTP/1.1\r\nHost: a\r\nX: {}v\r\n\r\n", value))), Some(431));
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        for headers in ["Host: a\r\nNo colon", "Host: a\r\nSpace : x", "Host: a\r\n: x", "X: 1", "Host: a\r\nhost: b"] {
+            assert_eq!(rejected(head(&format!("GET / HTTP/1.1\r\n{}\r\n\r\n", headers))), Some(400), "{:?}", headers);
+        }
+        // HTTP/1.0 requests do not need a host
+        assert_eq!(rejected(head("GET / HTTP/1.0\r\n\r\n")), None);
+    }
+
+    #[test]
+    fn reads_content_length_bodies() {
+        let (data, trailers) = body("Content-Length: 5\r\n", "hello, next request").ok().unwrap();
+        assert_eq!((data.as_slice(), trailers.len()), (&b"hello"[..], 0));
+        assert!(body("", "ignored").ok().unwrap().0.is_empty());
+        // repeated values that agree are one length
+        assert_eq!(body("Content-Length: 5, 5\r\nContent-Length: 5\r\n", "hello").ok().unwrap().0, b"hello");
+        assert!(matches!(body("Content-Length: 6\r\n", "hello"), Err(ParseError::Closed)));
+    }
+
+    #[test]
+    fn rejects_ambiguous_content_lengths() {
+        for headers in ["Content-Length: 5\r\nContent-Length: 6\r\n", "Content-Length: 5, 6\r\n", "Content-Length: +5\r\n", "Content-Length: 5 5\r\n", "Content-Length:\r\n", "Content-Length: 0x5\r\n"] {
+            assert_eq!(rejected(body(headers, "hello!")), Some(400), "{:?}", headers);
+        }
+        assert_eq!(rejected(body("Content-Length: 17\r\n", "")), Some(413));
+        assert_eq!(rejected(body("Content-Length: 99999999999999999999999\r\n", "")), Some(413));
+    }
+
+    #[test]
+    fn reads_chunked_bodies() {
+        let (data, trailers) = body("Transfer-Encoding: chunked\r\n", "5\r\nhello\r\n0\r\nChecksum: 1\r\n\r\n").ok().unwrap();
+        assert_eq!(data, b"hello");
+        assert_eq!(trailers, vec![(String::from("Checksum"), String::from("1"))]);
+        assert!(body("Transfer-Encoding: CHUNKED\r\n", "0\r\n\r\n").is_ok());
+    }
+
+    #[test]
+    fn rejects_smuggling_attempts() {
+        let chunked = "5\r\nhello\r\n0\r\n\r\n";
+        for headers in [
+            "Transfer-Encoding: chunked\r\nContent-Length: 5\r\n",
+            "Content-Length: 17\r\nTransfer-Encoding: chunked\r\n",
+            "Transfer-Encoding: chunked, identity\r\n",
+            "Transfer-Encoding: gzip\r\n",
+            "Transfer-Encoding: chunked\r\nTransfer-Encoding: gzip\r\n",
+            "Transfer-Encoding: xchunked\r\n",
+        ] {
+            assert_eq!(rejected(body(headers, chunked)), Some(400), "{:?}", headers);
+        }
+        assert_eq!(rejected(body("Transfer-Encoding: gzip, chunked\r\n", chunked)), Some(501));
+        assert_eq!(rejected(body("Transfer-Encoding: chunked\r\n", "5\r\nhello!\r\n0\r\n\r\n")), Some(400));
+    }
+
+    #[test]
+    fn enforces_chunked_limits() {
+        assert_eq!(rejected(body("Transfer-Encoding: chunked\r\n", "9\r\n123456789\r\n8\r\n12345678\r\n0\r\n\r\n")), Some(413));
+        let trailers = "A: 1\r\nB: 1\r\nC: 1\r\nD: 1\r\nE: 1\r\n";
+        assert_eq!(rejected(body("Transfer-Encoding: chunked\r\n", &format!("0\r\n{}\r\n", trailers))), Some(431));
+        assert_eq!(rejected(body("Transfer-Encoding: chunked\r\n", "0\r\nnot a field\r\n\r\n")), Some(400));
+    }
+
+    #[test]
+    fn large_bodies_are_written_to_a_temporary_file() {
+        let config = HttpServerConfig {
+            max_body_in_memory: Some(8),
+            ..config()
+        };
+        let (small, _) = stored_body("Content-Length: 8\r\n", "12345678", &config).ok().unwrap();
+        assert!(matches!(small, RequestBody::Memory(_)));
+
+        for (headers, data) in [("Content-Length: 9\r\n", "123456789"), ("Transfer-Encoding: chunked\r\n", "5\r\n12345\r\n4\r\n6789\r\n0\r\n\r\n")] {
+            let (large, _) = stored_body(headers, data, &config).ok().unwrap();
+            let path = match &large {
+                RequestBody::File { file, .. } => file.path().to_path_buf(),
+                RequestBody::Memory(_) => panic!("{:?} was kept in memory", headers),
+            };
+            assert_eq!(std::fs::read(&path).unwrap(), b"123456789");
+            assert_eq!(large.size(), 9);
+            drop(large);
+            assert!(!path.exists());
+        }
+    }
+}
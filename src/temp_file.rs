@@ -0,0 +1,51 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Counter making the names of the temporary files unique within the process
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Temporary file holding a large request body or part, it is removed when dropped unless `persist()` is called
+pub struct TempFile {
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl TempFile {
+    /** Create a new empty file in `dir`, its name starts with `prefix` */
+    pub(crate) fn create(dir: &Path, prefix: &str) -> io::Result<(Self, File)> {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.subsec_nanos());
+        let counter = TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("{}-{}-{}-{}", prefix, process::id(), counter, nanos));
+        let file = OpenOptions::new().write(true).create_new(true).open(&path)?;
+        Ok((Self { path, persisted: false }, file))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Move the file to `path` and keep it
+    pub fn persist<P: AsRef<Path>>(mut self, path: P) -> io::Result<()> {
+        if fs::rename(&self.path, &path).is_err() {
+            // renaming fails across file systems
+            fs::copy(&self.path, &path)?;
+            let _ = fs::remove_file(&self.path);
+        }
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
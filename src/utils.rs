@@ -1,33 +1,148 @@
-use std::{collections::HashMap, fs::{self, File}, error::Error};
+#[cfg(feature = "serde")]
+mod de;
+pub mod cookie;
+pub mod multipart;
+mod pattern;
+mod percent;
+mod random;
+pub mod session;
+mod tree;
+
+use std::{collections::HashMap, error::Error, fmt, fs::{self, File}, sync::{Arc, RwLock}};
 
 use mime_guess::{MimeGuess};
 
-use crate::{HttpRequest, HttpServer, HttpStatusStruct, HttpResponse, RequestHandleFunc, RequestErrorHandleFunc};
+use crate::{HandlerResult, HttpRequest, HttpServer, HttpStatusStruct, HttpResponse, Next, RequestHandleFunc};
+
+#[cfg(feature = "secure-cookies")]
+use cookie::CookieKey;
+use pattern::RoutePattern;
+use session::Session;
+use tree::RouteTree;
+
+/// Middleware of a `Router` or a `Route`, shared by the routes of a router
+type Layer = Arc<dyn Fn(HttpRequest, HttpResponse, Next) -> HandlerResult + Send + Sync + 'static>;
+
+/// Why `url_for()` could not build a URL
+#[derive(Debug)]
+pub enum UrlForError {
+    /// No route has this name
+    UnknownRoute(String),
+    /// The route pattern needs a parameter that was not given
+    MissingParam { route: String, param: String },
+}
+
+/// Why the data of a request could not be read into a type by `TypedRequest`.
+/// The default error handler of the server answers it with `status()` and the message, when a handler returns it as its error.
+#[cfg(feature = "serde")]
+#[derive(Debug)]
+pub enum ExtractError {
+    /// The request body does not have the expected `Content-Type`
+    UnsupportedMediaType { expected: &'static str },
+    /// The data does not match the type. `field` is the name of the field in error when it is known.
+    Invalid { field: Option<String>, message: String },
+}
 
-/// Stand alone function for breaking `HttpRequest` into path and params
-/// ```rust
+/// Stand alone function for breaking `HttpRequest` into path and params.
+/// The path is percent-decoded and normalized (see `normalize_path()`), the fragment is ignored and when a parameter is
+/// repeated the last value is kept (use `MoreDetailsRequest::query_pairs()` to get all of them).
+/// ```rust,ignore
 /// server.insert_handler(|req, res| {
 ///     let (path, params) = break_request_uri(&req);
 ///     Ok((req, res))
 /// });
 /// ```
 pub fn break_request_uri(req: &HttpRequest) -> (String, HashMap<String, String>) {
-    let uri = req.uri();
-    let parts: Vec<&str> = uri.split('?').collect();
-    let mut params = HashMap::<String, String>::new();
-    let path = String::from(if let Some(path) = parts.get(0) { path } else { "/" });
-    if parts.len() >= 2 {
-        let pairs: Vec<&str> = parts[1].split('&').collect();
-        for pair in pairs {
-            let key_val: Vec<&str> = pair.split('=').collect();
-            params.insert(String::from(key_val[0]), String::from(if let Some(val) = key_val.get(1) { val } else { "" }));
+    let (path, query) = split_request_uri(req.uri());
+    let path = normalize_path(&percent::decode(path));
+    let params: HashMap<String, String> = parse_query_string(query.unwrap_or("")).into_iter().collect();
+    (path, params)
+}
+
+/// Decode an `application/x-www-form-urlencoded` string such as a query string into its key - value pairs, in order.
+/// `+` is decoded as a space, and a pair without `=` has an empty value.
+/// ```rust,ignore
+/// let pairs = parse_query_string("tag=a&tag=b&q=hello+world%21");
+/// // [("tag", "a"), ("tag", "b"), ("q", "hello world!")]
+/// ```
+pub fn parse_query_string(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent::decode_query(key), percent::decode_query(value))
+        })
+        .collect()
+}
+
+/// Resolve the `.` and `..` segments of a path and merge repeated slashes. The result starts with `/`, a `..` segment never
+/// goes above the root, and the trailing slash is kept.
+/// ```rust,ignore
+/// assert_eq!(normalize_path("//a/./b/../c/"), "/a/c/");
+/// assert_eq!(normalize_path("/../../etc/passwd"), "/etc/passwd");
+/// ```
+pub fn normalize_path(path: &str) -> String {
+    let mut segments = Vec::<&str>::new();
+    let mut trailing_slash = false;
+    for segment in path.split('/') {
+        trailing_slash = matches!(segment, "" | "." | "..");
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
         }
     }
-    (path, params)
+
+    let mut normalized = String::with_capacity(path.len() + 1);
+    for segment in segments.iter() {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if trailing_slash || normalized.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
+
+/**
+ * The segments of the request path used for routing: each segment is percent-decoded on its own, so an encoded `/` (`%2F`)
+ * stays in its segment. Like `normalize_path()`, empty and `.` segments are dropped, `..` removes the segment before it and
+ * a trailing slash gives a last empty segment (`/` is `[""]`).
+ */
+fn route_segments(uri: &str) -> Vec<String> {
+    let mut segments = Vec::<String>::new();
+    let mut trailing_slash = false;
+    for segment in split_request_uri(uri).0.split('/') {
+        let segment = percent::decode(segment);
+        trailing_slash = matches!(segment.as_str(), "" | "." | "..");
+        match segment.as_str() {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    if trailing_slash || segments.is_empty() {
+        segments.push(String::new());
+    }
+    segments
+}
+
+/** Split a request target into its path and query string, the fragment is dropped */
+fn split_request_uri(uri: &str) -> (&str, Option<&str>) {
+    let uri = uri.split_once('#').map_or(uri, |(uri, _)| uri);
+    match uri.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (uri, None),
+    }
 }
 
 /// Provide more details for `HttpRequest`
-/// ```rust
+/// ```rust,ignore
 /// server.insert_handler(|req, res| {
 ///     let path = req.path();
 ///     let params = req.params();
@@ -38,22 +153,188 @@ pub trait MoreDetailsRequest {
     /// Get request's path
     fn path(&self) -> String;
 
-    /// Get request's parameters
+    /// Get request's parameters, the last value is kept when a parameter is repeated
     fn params(&self) -> HashMap<String, String>;
+
+    /// Get request's parameters in order, including repeated ones (`?tag=a&tag=b`)
+    fn query_pairs(&self) -> Vec<(String, String)>;
+
+    /// Get every value of a repeated parameter
+    fn param_all(&self, name: &str) -> Vec<String>;
+
+    /// Get the values captured by the matched `Router` route pattern (empty outside a route)
+    fn route_params(&self) -> HashMap<String, String>;
+
+    /// Get one value captured by the matched `Router` route pattern
+    fn route_param(&self, name: &str) -> Option<String>;
+
+    /// Get the part of the path matched by the prefixes of the mounted routers the route belongs to (empty outside a mounted router)
+    fn mount_prefix(&self) -> String;
+
+    /// Get the path without the mount prefix, as seen by the mounted router
+    fn mount_path(&self) -> String;
+
+    /// Get the cookies sent with the request, the first one is kept when a name is repeated
+    fn cookies(&self) -> HashMap<String, String>;
+
+    /// Get one cookie sent with the request
+    fn cookie(&self, name: &str) -> Option<String>;
+
+    /// Get the session of the request, `None` when the session middleware (`session::middleware()`) did not run before
+    fn session(&self) -> Option<Session>;
+
+    /// Get a cookie written with `Cookie::signed()`, `None` when it is missing or was changed by the client
+    #[cfg(feature = "secure-cookies")]
+    fn signed_cookie(&self, key: &CookieKey, name: &str) -> Option<String>;
+
+    /// Get a cookie written with `Cookie::encrypted()`, `None` when it is missing or was changed by the client
+    #[cfg(feature = "secure-cookies")]
+    fn private_cookie(&self, key: &CookieKey, name: &str) -> Option<String>;
 }
 
 impl MoreDetailsRequest for HttpRequest {
     fn path(&self) -> String {
-        break_request_uri(&self).0
+        break_request_uri(self).0
     }
 
     fn params(&self) -> HashMap<String, String> {
-        break_request_uri(&self).1
+        break_request_uri(self).1
+    }
+
+    fn query_pairs(&self) -> Vec<(String, String)> {
+        parse_query_string(split_request_uri(self.uri()).1.unwrap_or(""))
+    }
+
+    fn param_all(&self, name: &str) -> Vec<String> {
+        self.query_pairs()
+            .into_iter()
+            .filter(|(key, _)| key == name)
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    fn route_params(&self) -> HashMap<String, String> {
+        self.route_params.clone()
+    }
+
+    fn route_param(&self, name: &str) -> Option<String> {
+        self.route_params.get(name).cloned()
+    }
+
+    fn mount_prefix(&self) -> String {
+        self.mount_prefix.clone()
+    }
+
+    fn mount_path(&self) -> String {
+        let path = self.path();
+        match path.strip_prefix(&self.mount_prefix) {
+            Some("") => String::from("/"),
+            Some(rest) => String::from(rest),
+            None => path,
+        }
+    }
+
+    fn cookies(&self) -> HashMap<String, String> {
+        let mut cookies = HashMap::<String, String>::new();
+        for header in self.headers().get_all("Cookie") {
+            for (name, value) in cookie::parse_cookie_header(header) {
+                cookies.entry(name).or_insert(value);
+            }
+        }
+        cookies
+    }
+
+    fn cookie(&self, name: &str) -> Option<String> {
+        self.headers()
+            .get_all("Cookie")
+            .flat_map(cookie::parse_cookie_header)
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+    }
+
+    fn session(&self) -> Option<Session> {
+        self.session.clone()
+    }
+
+    #[cfg(feature = "secure-cookies")]
+    fn signed_cookie(&self, key: &CookieKey, name: &str) -> Option<String> {
+        key.verify(name, &self.cookie(name)?)
+    }
+
+    #[cfg(feature = "secure-cookies")]
+    fn private_cookie(&self, key: &CookieKey, name: &str) -> Option<String> {
+        key.decrypt(name, &self.cookie(name)?)
+    }
+}
+
+/// Deserialize the query string, the form body or the JSON body of `HttpRequest` into a type (requires the `serde` feature,
+/// and the `json` feature for `json()`).
+/// A parameter repeated in the request fills a sequence field, such as a `Vec<String>`.
+/// ```rust,ignore
+/// #[derive(Deserialize)]
+/// struct Search {
+///     q: String,
+///     page: Option<u32>,
+///     tag: Vec<String>,
+/// }
+///
+/// server.insert_handler(|req, res| {
+///     // GET /search?q=rust&tag=web&tag=http
+///     let search: Search = match req.query() {
+///         Ok(search) => search,
+///         Err(e) => return Err((req, res, Box::new(e))),     // 400 Bad Request: Invalid field `page`: ...
+///     };
+///     Ok((req, res))
+/// });
+/// ```
+#[cfg(feature = "serde")]
+pub trait TypedRequest {
+    /// Deserialize the query string
+    fn query<T: serde::de::DeserializeOwned>(&self) -> Result<T, ExtractError>;
+
+    /// Deserialize an `application/x-www-form-urlencoded` body
+    fn form<T: serde::de::DeserializeOwned>(&self) -> Result<T, ExtractError>;
+
+    /// Deserialize a JSON body, the `Content-Type` must be `application/json` or end with `+json` (requires the `json` feature)
+    #[cfg(feature = "json")]
+    fn json<T: serde::de::DeserializeOwned>(&self) -> Result<T, ExtractError>;
+}
+
+#[cfg(feature = "serde")]
+impl TypedRequest for HttpRequest {
+    fn query<T: serde::de::DeserializeOwned>(&self) -> Result<T, ExtractError> {
+        de::from_pairs(self.query_pairs())
+    }
+
+    fn form<T: serde::de::DeserializeOwned>(&self) -> Result<T, ExtractError> {
+        const FORM: &str = "application/x-www-form-urlencoded";
+        if !media_type(self).eq_ignore_ascii_case(FORM) {
+            return Err(ExtractError::UnsupportedMediaType { expected: FORM });
+        }
+        de::from_pairs(parse_query_string(&String::from_utf8_lossy(self.body())))
+    }
+
+    #[cfg(feature = "json")]
+    fn json<T: serde::de::DeserializeOwned>(&self) -> Result<T, ExtractError> {
+        let media_type = media_type(self).to_ascii_lowercase();
+        if media_type != "application/json" && !media_type.ends_with("+json") {
+            return Err(ExtractError::UnsupportedMediaType { expected: "application/json" });
+        }
+        serde_json::from_slice(self.body()).map_err(|e| ExtractError::Invalid {
+            field: None,
+            message: e.to_string(),
+        })
     }
 }
 
+/** `Content-Type` of the request without its parameters */
+#[cfg(feature = "serde")]
+fn media_type(req: &HttpRequest) -> &str {
+    req.headers().get("Content-Type").and_then(|value| value.split(';').next()).unwrap_or("").trim()
+}
+
 /// Provide `HttpServer` the ability to serve static files
-/// ```rust
+/// ```rust,ignore
 /// server.serve_static(None);      // Default folder is "public"
 /// server.serve_static(Some(String::from("your_dir")));
 /// ```
@@ -66,20 +347,25 @@ impl ServeStatic for HttpServer {
     fn serve_static(&mut self, root_dir: Option<String>) {
         let roor_dir = root_dir.unwrap_or(String::from("public"));
         self.insert_handler(move |req, mut res| {
+            // the path is normalized, so it cannot leave the root dir with `..`
             let path = req.path();
             let prefix = format!("/{}", &roor_dir);
-            if let Some(index) = path.find(&prefix) {
-                if index == 0 {
-                    let path = String::from(&path[prefix.len()..path.len()]);
+            if let Some(path) = path.strip_prefix(&prefix) {
+                if path.starts_with('/') {
                     let file_path = format!("{}{}", &roor_dir, &path);
                     if let Ok(file) = File::open(&file_path) {
                         if let Ok(metadata) = file.metadata() {
                             if metadata.is_file() {
-                                let data = fs::read(&file_path).unwrap_or(Vec::new());
                                 let guess = MimeGuess::from_path(&file_path);
                                 res.set_status(HttpStatusStruct(200, "OK"));
                                 res.insert_header(String::from("Content-Type"), guess.first_or(mime_guess::mime::TEXT_PLAIN).to_string());
-                                res.bytes(data);
+                                if req.method() == "HEAD" {
+                                    // the server keeps this length for a HEAD response without body
+                                    res.insert_header(String::from("Content-Length"), metadata.len().to_string());
+                                } else {
+                                    let data = fs::read(&file_path).unwrap_or(Vec::new());
+                                    res.bytes(data);
+                                }
                             }
                         }
                     }
@@ -90,73 +376,114 @@ impl ServeStatic for HttpServer {
     }
 }
 
-/// Route definition
-pub struct Route(String, RequestHandleFunc);
+/// Route definition. The path is a pattern made of `/` separated segments:
+/// - `users` matches the text exactly
+/// - `:id` matches any non-empty segment and captures it as `id`
+/// - `:page?` is the same as `:page` but the segment may be missing
+/// - `*rest` matches the rest of the path (possibly empty) and captures it as `rest`, it must be the last segment
+///
+/// Captured values are available through `MoreDetailsRequest::route_params()`.
+/// ```rust,ignore
+/// router.define_route(Route::get("/users/:id", |req, mut res| {
+///     let id = req.route_param("id").unwrap();
+///     res.set_status(HttpStatusStruct(200, "OK"));
+///     res.text(format!("User {}", id));
+///     Ok((req, res))
+/// }));
+/// ```
+pub struct Route {
+    pattern: RoutePattern,
+    name: Option<String>,
+    method: Option<String>,
+    handler: RequestHandleFunc,
+    /// Middleware around the handler, outermost first (including the layers of the mounted routers)
+    layers: Vec<Layer>,
+    /// Number of leading pattern segments that come from mount prefixes
+    mount_depth: usize,
+}
 
 impl Route {
+    fn with_method<F>(method: Option<&str>, path: &str, handler: F) -> Self
+            where F: Fn(HttpRequest, HttpResponse) -> HandlerResult + Send + Sync + 'static {
+        Self {
+            pattern: RoutePattern::parse(path),
+            name: None,
+            method: method.map(String::from),
+            handler: Box::new(handler),
+            layers: Vec::new(),
+            mount_depth: 0,
+        }
+    }
+
+    /// Handle every method
     pub fn all<F>(path: &str, handler: F) -> Self
-            where F: Fn(HttpRequest, HttpResponse) -> Result<(HttpRequest, HttpResponse), (HttpRequest, HttpResponse, Box<dyn Error>)> + Send + Sync + 'static {
-        Self(String::from(path), Box::new(handler))
+            where F: Fn(HttpRequest, HttpResponse) -> HandlerResult + Send + Sync + 'static {
+        Self::with_method(None, path, handler)
     }
 
     pub fn get<F>(path: &str, handler: F) -> Self
-            where F: Fn(HttpRequest, HttpResponse) -> Result<(HttpRequest, HttpResponse), (HttpRequest, HttpResponse, Box<dyn Error>)> + Send + Sync + 'static {
-        Self(String::from(path), Box::new(move |req, res| {
-            if req.method() == "GET" {
-                handler(req, res)
-            } else {
-                Ok((req, res))
-            }
-        }))
+            where F: Fn(HttpRequest, HttpResponse) -> HandlerResult + Send + Sync + 'static {
+        Self::with_method(Some("GET"), path, handler)
     }
 
     pub fn post<F>(path: &str, handler: F) -> Self
-            where F: Fn(HttpRequest, HttpResponse) -> Result<(HttpRequest, HttpResponse), (HttpRequest, HttpResponse, Box<dyn Error>)> + Send + Sync + 'static {
-        Self(String::from(path), Box::new(move |req, res| {
-            if req.method() == "POST" {
-                handler(req, res)
-            } else {
-                Ok((req, res))
-            }
-        }))
+            where F: Fn(HttpRequest, HttpResponse) -> HandlerResult + Send + Sync + 'static {
+        Self::with_method(Some("POST"), path, handler)
     }
     
     pub fn put<F>(path: &str, handler: F) -> Self
-            where F: Fn(HttpRequest, HttpResponse) -> Result<(HttpRequest, HttpResponse), (HttpRequest, HttpResponse, Box<dyn Error>)> + Send + Sync + 'static {
-        Self(String::from(path), Box::new(move |req, res| {
-            if req.method() == "PUT" {
-                handler(req, res)
-            } else {
-                Ok((req, res))
-            }
-        }))
+            where F: Fn(HttpRequest, HttpResponse) -> HandlerResult + Send + Sync + 'static {
+        Self::with_method(Some("PUT"), path, handler)
     }
 
     pub fn patch<F>(path: &str, handler: F) -> Self
-            where F: Fn(HttpRequest, HttpResponse) -> Result<(HttpRequest, HttpResponse), (HttpRequest, HttpResponse, Box<dyn Error>)> + Send + Sync + 'static {
-        Self(String::from(path), Box::new(move |req, res| {
-            if req.method() == "PATCH" {
-                handler(req, res)
-            } else {
-                Ok((req, res))
-            }
-        }))
+            where F: Fn(HttpRequest, HttpResponse) -> HandlerResult + Send + Sync + 'static {
+        Self::with_method(Some("PATCH"), path, handler)
     }
 
     pub fn delete<F>(path: &str, handler: F) -> Self
-            where F: Fn(HttpRequest, HttpResponse) -> Result<(HttpRequest, HttpResponse), (HttpRequest, HttpResponse, Box<dyn Error>)> + Send + Sync + 'static {
-        Self(String::from(path), Box::new(move |req, res| {
-            if req.method() == "DELETE" {
-                handler(req, res)
-            } else {
-                Ok((req, res))
-            }
-        }))
+            where F: Fn(HttpRequest, HttpResponse) -> HandlerResult + Send + Sync + 'static {
+        Self::with_method(Some("DELETE"), path, handler)
+    }
+
+    /// Handle `HEAD` yourself, otherwise `HEAD` requests are handled by the `GET` route of the path without the body
+    pub fn head<F>(path: &str, handler: F) -> Self
+            where F: Fn(HttpRequest, HttpResponse) -> HandlerResult + Send + Sync + 'static {
+        Self::with_method(Some("HEAD"), path, handler)
+    }
+
+    /// Handle `OPTIONS` yourself instead of the automatic answer of the router
+    pub fn options<F>(path: &str, handler: F) -> Self
+            where F: Fn(HttpRequest, HttpResponse) -> HandlerResult + Send + Sync + 'static {
+        Self::with_method(Some("OPTIONS"), path, handler)
+    }
+
+    /// Name the route so its URL can be built with `Router::url_for()`
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(String::from(name));
+        self
+    }
+
+    /// Run `middleware` around the handler of this route only. Middleware added first runs first.
+    /// ```rust,ignore
+    /// router.define_route(Route::post("/upload", upload).with(|req, mut res, next| {
+    ///     if req.body_size() > 1024 * 1024 {
+    ///         res.set_status(HttpStatusStruct(413, "Content Too Large"));
+    ///         res.end();
+    ///         return Ok((req, res));
+    ///     }
+    ///     next.run(req, res)
+    /// }));
+    /// ```
+    pub fn with<F>(mut self, middleware: F) -> Self
+            where F: Fn(HttpRequest, HttpResponse, Next) -> HandlerResult + Send + Sync + 'static {
+        self.layers.push(Arc::new(middleware));
+        self
     }
 }
 
 /// A standard router provides basic routing support.
-/// ```rust
+/// ```rust,ignore
 /// let mut router = Router::new();
 /// // define a route to handle request when client call GET /test/
 /// router.define_route(Route::get("/test/", |req, res| {
@@ -166,28 +493,286 @@ impl Route {
 ///     Ok((req, res))
 /// }));
 /// ```
-/// Be mindful of the define order of the routes, for example:
-/// ```rust
-/// router.define_route(Route::all("/test/", |req, res| {...}));
-/// router.define_route(Route::get("/test/", |req, res| {...}));    // This will be called again if client request with a GET method
+/// When several patterns match a path, the most specific one handles the request whatever the define order:
+/// segments are compared from left to right and a static segment beats a parameter, which beats an optional parameter,
+/// which beats a wildcard.
+/// ```rust,ignore
+/// router.define_route(Route::get("/users/:id", |req, res| {...}));
+/// router.define_route(Route::get("/users/me", |req, res| {...}));     // GET /users/me is handled here
+/// router.define_route(Route::get("/files/*rest", |req, res| {...}));  // GET /files/a/b.txt captures rest = "a/b.txt"
 /// ```
-/// Therefore, you should be careful when define routes.
+/// Only routes of the request method are considered, and a route of the request method is preferred to a `Route::all` route
+/// with the same pattern. `HEAD` is handled like `GET`, by the same route, unless that pattern also has a `HEAD` route.
+/// When the path matches a route but none of them handles the method, the router answers `405 Method Not Allowed`
+/// (or `204 No Content` for `OPTIONS`) with an `Allow` header listing the methods defined for the path.
+///
+/// Routes are compiled into a tree keyed by path segment when they are defined, so finding the route of a request does not
+/// depend on the number of routes. Two routes of the same method matching the same paths (for example `/users/:id` and
+/// `/users/:name`) are ambiguous and `define_route()` panics on the second one.
 pub struct Router {
-    routes: Vec<Route>
+    routes: Vec<Route>,
+    tree: RouteTree,
+    layers: Vec<Layer>,
+    urls: RouteUrls,
+    /// URL tables of the mounted routers, kept up to date with the prefixes their routes are served under
+    mounted_urls: Vec<RouteUrls>,
+}
+
+/// Named route patterns of a `Router`, to build URLs from the handlers once the router is given to the server.
+/// It is cheap to clone and sees the routes defined or mounted after it was created.
+/// ```rust,ignore
+/// let mut router = Router::new();
+/// let urls = router.urls();
+/// router.define_route(Route::post("/users", move |req, mut res| {
+///     let id = create_user(&req);
+///     res.set_status(HttpStatusStruct(201, "Created"));
+///     res.insert_header(String::from("Location"), urls.url_for("user", &[("id", &id)]).unwrap());
+///     Ok((req, res))
+/// }));
+/// router.define_route(Route::get("/users/:id", get_user).name("user"));
+/// ```
+#[derive(Clone, Default)]
+pub struct RouteUrls {
+    patterns: Arc<RwLock<HashMap<String, RoutePattern>>>,
 }
 
 impl Router {
     pub fn new() -> Self {
-        Self { routes: Vec::new() }
+        Self {
+            routes: Vec::new(),
+            tree: RouteTree::default(),
+            layers: Vec::new(),
+            urls: RouteUrls::default(),
+            mounted_urls: Vec::new(),
+        }
     }
 
+    /// Add a route, panics when it conflicts with an already defined route or its name is already used
+    #[track_caller]
     pub fn define_route(&mut self, route: Route) {
+        let index = self.routes.len();
+        if let Some(name) = &route.name {
+            let mut patterns = self.urls.patterns.write().unwrap();
+            if let Some(pattern) = patterns.get(name) {
+                panic!("Route `{}` is named `{}` like the route `{}` defined before", route.pattern, name, pattern);
+            }
+            patterns.insert(name.clone(), route.pattern.clone());
+        }
+        let optional = route.pattern.has_optional();
+        for variant in route.pattern.variants() {
+            if let Err(conflict) = self.tree.insert(&variant, optional, route.method.as_deref(), index) {
+                let conflict = &self.routes[conflict];
+                panic!(
+                    "Route `{} {}` conflicts with the route `{} {}` defined before",
+                    route.method.as_deref().unwrap_or("ALL"),
+                    route.pattern,
+                    conflict.method.as_deref().unwrap_or("ALL"),
+                    conflict.pattern
+                );
+            }
+        }
         self.routes.push(route);
     }
+
+    /// Run `middleware` around every route of this router, including the routes of mounted routers, whatever the order
+    /// `layer()` and `define_route()` are called. Middleware added first runs first.
+    ///
+    /// The middleware only runs when a route of the router handles the request: the requests that the router does not handle
+    /// (including its automatic `405` and `OPTIONS` answers) go on to the rest of the server's chain without it.
+    /// For a request, the server's chain runs first, then the layers of this router, the layers of the mounted routers
+    /// from the outermost one, the middleware of the route and finally the route handler.
+    /// ```rust,ignore
+    /// let mut admin = Router::new();
+    /// admin.layer(|req, mut res, next| {
+    ///     if req.headers().get("Authorization") != Some("Bearer secret") {
+    ///         res.set_status(HttpStatusStruct(401, "Unauthorized"));
+    ///         res.end();
+    ///         return Ok((req, res));
+    ///     }
+    ///     next.run(req, res)
+    /// });
+    /// router.mount("/admin", admin);
+    /// ```
+    pub fn layer<F>(&mut self, middleware: F)
+            where F: Fn(HttpRequest, HttpResponse, Next) -> HandlerResult + Send + Sync + 'static {
+        self.layers.push(Arc::new(middleware));
+    }
+
+    /// Serve the routes of `router` under `prefix`. The prefix can have `:name` segments but no optional or wildcard segment.
+    /// Inner handlers see the whole path with `path()`, and the matched prefix and the rest of the path with
+    /// `mount_prefix()` and `mount_path()`.
+    /// ```rust,ignore
+    /// let mut users = Router::new();
+    /// users.define_route(Route::get("/", |req, res| {...}));         // GET /api/v1/users
+    /// users.define_route(Route::get("/:id", |req, res| {...}));      // GET /api/v1/users/42, mount_prefix() is "/api/v1/users"
+    ///
+    /// let mut api = Router::new();
+    /// api.mount("/users", users);
+    ///
+    /// let mut router = Router::new();
+    /// router.mount("/api/v1", api);
+    /// server.insert_router(router);
+    /// ```
+    #[track_caller]
+    pub fn mount(&mut self, prefix: &str, router: Router) {
+        let prefix = RoutePattern::parse_prefix(prefix);
+        let mut mounted_urls = router.mounted_urls;
+        mounted_urls.push(router.urls);
+        for mut route in router.routes {
+            route.pattern = route.pattern.nest(&prefix);
+            route.mount_depth += prefix.len();
+            route.layers.splice(0..0, router.layers.iter().cloned());
+            if let Some(name) = &route.name {
+                for urls in mounted_urls.iter() {
+                    urls.patterns.write().unwrap().insert(name.clone(), route.pattern.clone());
+                }
+            }
+            self.define_route(route);
+        }
+        self.mounted_urls.extend(mounted_urls);
+    }
+
+    /// Build the URL of the route named `name`. The values of `params` replace the parameters of the route pattern and are
+    /// percent-encoded, the other `params` make the query string. Fails when there is no such route or a parameter is missing
+    /// (an optional parameter can be left out).
+    /// ```rust,ignore
+    /// router.define_route(Route::get("/users/:id/posts/:page?", handler).name("user_posts"));
+    /// router.url_for("user_posts", &[("id", "42")])?;                          // "/users/42/posts"
+    /// router.url_for("user_posts", &[("id", "a b"), ("sort", "new")])?;        // "/users/a%20b/posts?sort=new"
+    /// ```
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> Result<String, UrlForError> {
+        self.urls.url_for(name, params)
+    }
+
+    /// Shareable table of the named routes of this router, see `RouteUrls`
+    pub fn urls(&self) -> RouteUrls {
+        self.urls.clone()
+    }
+
+    /**
+     * Call the most specific route that matches both the path and the method of the request, `HEAD` falls back to the `GET` routes.
+     * When the path matches but the method does not, answer `OPTIONS` with `204` and other methods with `405`,
+     * both with an `Allow` header listing the methods of the path.
+     */
+    fn handle(&self, mut req: HttpRequest, mut res: HttpResponse) -> HandlerResult {
+        let segments = route_segments(req.uri());
+        let parts: Vec<&str> = segments.iter().map(String::as_str).collect();
+        let candidates = self.tree.lookup(&parts);
+        if candidates.is_empty() {
+            return Ok((req, res));
+        }
+
+        // the most specific pattern handling the method wins, `HEAD` is answered like `GET` unless the pattern has a `HEAD` route
+        let method = req.method().as_str();
+        let matched = candidates.iter().find_map(|leaves| {
+            let route = |allowed: Option<&str>| leaves.iter().find(|(other, _)| other.as_deref() == allowed);
+            route(Some(method))
+                .or_else(|| if method == "HEAD" { route(Some("GET")) } else { None })
+                .or_else(|| route(None))
+        });
+        if let Some((_, index)) = matched {
+            let route = &self.routes[*index];
+            req.route_params = route.pattern.matches(&parts).unwrap_or_default();
+            req.mount_prefix = if route.mount_depth > 0 {
+                format!("/{}", parts[..route.mount_depth.min(parts.len())].join("/"))
+            } else {
+                String::new()
+            };
+            let layers: Vec<&Layer> = self.layers.iter().chain(route.layers.iter()).collect();
+            return Router::run_layers(&layers, &route.handler, req, res);
+        }
+
+        let mut allowed = Vec::<&str>::new();
+        for method in candidates.iter().flat_map(|leaves| leaves.iter()).filter_map(|(method, _)| method.as_deref()) {
+            if !allowed.contains(&method) {
+                allowed.push(method);
+            }
+        }
+        if allowed.contains(&"GET") && !allowed.contains(&"HEAD") {
+            allowed.push("HEAD");
+        }
+        if !allowed.contains(&"OPTIONS") {
+            allowed.push("OPTIONS");
+        }
+        res.insert_header(String::from("Allow"), allowed.join(", "));
+        if req.method() == "OPTIONS" {
+            res.set_status(HttpStatusStruct(204, "No Content"));
+        } else {
+            res.set_status(HttpStatusStruct(405, "Method Not Allowed"));
+        }
+        Ok((req, res))
+    }
+
+    /** Run the middleware in order around `handler`, a middleware that ends the response skips the ones after it */
+    fn run_layers(layers: &[&Layer], handler: &RequestHandleFunc, req: HttpRequest, res: HttpResponse) -> HandlerResult {
+        if res.is_ended() {
+            return Ok((req, res));
+        }
+        match layers.split_first() {
+            Some((layer, rest)) => layer(req, res, Next {
+                inner: &|req, res| Router::run_layers(rest, handler, req, res),
+            }),
+            None => handler(req, res),
+        }
+    }
+}
+
+impl RouteUrls {
+    /// Build the URL of the route named `name`, see `Router::url_for()`
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> Result<String, UrlForError> {
+        let patterns = self.patterns.read().unwrap();
+        let pattern = patterns.get(name).ok_or_else(|| UrlForError::UnknownRoute(String::from(name)))?;
+        pattern.url(params).map_err(|param| UrlForError::MissingParam {
+            route: String::from(name),
+            param,
+        })
+    }
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for UrlForError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UrlForError::UnknownRoute(name) => write!(f, "No route is named `{}`", name),
+            UrlForError::MissingParam { route, param } => write!(f, "Missing parameter `{}` for the route `{}`", param, route),
+        }
+    }
+}
+
+impl Error for UrlForError {}
+
+#[cfg(feature = "serde")]
+impl ExtractError {
+    /// Status of the response to a request with this error
+    pub fn status(&self) -> HttpStatusStruct {
+        match self {
+            ExtractError::UnsupportedMediaType { .. } => HttpStatusStruct(415, "Unsupported Media Type"),
+            ExtractError::Invalid { .. } => HttpStatusStruct(400, "Bad Request"),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl fmt::Display for ExtractError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExtractError::UnsupportedMediaType { expected } => write!(f, "Expected a request body of type {}", expected),
+            ExtractError::Invalid { field: Some(field), message } => write!(f, "Invalid field `{}`: {}", field, message),
+            ExtractError::Invalid { field: None, message } => write!(f, "Invalid data: {}", message),
+        }
+    }
 }
 
+#[cfg(feature = "serde")]
+impl Error for ExtractError {}
+
 /// Provide `HttpServer` the `insert_router()` function.
-/// ```rust
+/// ```rust,ignore
 /// let mut router = Router::new();
 /// 
 /// // Begin defining routes
@@ -202,18 +787,54 @@ pub trait Routing {
 
 impl Routing for HttpServer {
     fn insert_router(&mut self, router: Router) {
-        self.insert_handler(move |req, res| {
-            let path = req.path();
-            let mut routes = router.routes.iter();
-            loop {
-                if let Some(route) = routes.next() {
-                    if route.0 == path {
-                        break route.1(req, res);
-                    }
-                } else {
-                    break Ok((req, res));
-                }
-            }
-        });
+        self.insert_handler(move |req, res| router.handle(req, res));
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_path_resolves_dot_segments() {
+        assert_eq!(normalize_path("//a/./b/../c/"), "/a/c/");
+        assert_eq!(normalize_path("/a/b/.."), "/a/");
+        assert_eq!(normalize_path(""), "/");
+        assert_eq!(normalize_path("/"), "/");
+        assert_eq!(normalize_path("a/b"), "/a/b");
+    }
+
+    #[test]
+    fn normalize_path_never_goes_above_root() {
+        assert_eq!(normalize_path("/../../etc/passwd"), "/etc/passwd");
+        assert_eq!(normalize_path("/public/../../../etc/passwd"), "/etc/passwd");
+        assert_eq!(normalize_path("/.."), "/");
+        assert_eq!(normalize_path(&percent::decode("/%2e%2e/%2E%2E/etc/passwd")), "/etc/passwd");
+        assert_eq!(normalize_path(&percent::decode("/a/..%2F..%2Fsecret")), "/secret");
+    }
+
+    #[test]
+    fn route_segments_decode_each_segment() {
+        assert_eq!(route_segments("/users/a%2Fb"), vec!["users", "a/b"]);
+        assert_eq!(route_segments("/users/a%20b?x=%2F#top"), vec!["users", "a b"]);
+        assert_eq!(route_segments("/a//b/./c/../d/"), vec!["a", "b", "d", ""]);
+        assert_eq!(route_segments("/%2E%2E/a"), vec!["a"]);
+        assert_eq!(route_segments("/"), vec![""]);
+        assert_eq!(route_segments(""), vec![""]);
+    }
+
+    #[test]
+    fn query_string_pairs_are_decoded_in_order() {
+        assert_eq!(
+            parse_query_string("tag=a&tag=b&q=hello+world%21&flag&=x&&k=v=w"),
+            vec![
+                (String::from("tag"), String::from("a")),
+                (String::from("tag"), String::from("b")),
+                (String::from("q"), String::from("hello world!")),
+                (String::from("flag"), String::new()),
+                (String::new(), String::from("x")),
+                (String::from("k"), String::from("v=w")),
+            ]
+        );
+    }
+}
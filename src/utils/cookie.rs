@@ -0,0 +1,460 @@
+//! Cookies (RFC 6265): `Cookie` builds a `Set-Cookie` header for `HttpResponse::add_cookie()`, and `MoreDetailsRequest::cookies()`
+//! reads the `Cookie` header of a request.
+//!
+//! With the `secure-cookies` feature, `CookieKey` signs cookies (the client can read but not change them) or encrypts them
+//! (the client can neither read nor change them).
+//!
+//! Example:
+//! ```rust,ignore
+//! use speed_rs_core::utils::{cookie::{Cookie, SameSite}, MoreDetailsRequest};
+//!
+//! server.insert_handler(|req, mut res| {
+//!     let theme = req.cookie("theme").unwrap_or(String::from("light"));
+//!     res.add_cookie(Cookie::new("theme", &theme).path("/").max_age(Duration::from_secs(30 * 24 * 3600)).same_site(SameSite::Lax));
+//!     res.add_cookie(Cookie::removal("legacy"));
+//!     Ok((req, res))
+//! });
+//! ```
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(feature = "secure-cookies")]
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+#[cfg(feature = "secure-cookies")]
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+#[cfg(feature = "secure-cookies")]
+use hmac::{Hmac, Mac};
+#[cfg(feature = "secure-cookies")]
+use sha2::Sha256;
+
+use super::percent;
+#[cfg(feature = "secure-cookies")]
+use super::random;
+
+#[cfg(feature = "secure-cookies")]
+type HmacSha256 = Hmac<Sha256>;
+
+/// Size of the nonce of ChaCha20-Poly1305
+#[cfg(feature = "secure-cookies")]
+const NONCE_SIZE: usize = 12;
+
+/// Cookie sent to the client with a `Set-Cookie` header.
+/// The value is percent-encoded when it is sent and `MoreDetailsRequest::cookies()` decodes it, so it can hold any text.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    expires: Option<SystemTime>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+/// `SameSite` attribute of a cookie
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SameSite {
+    /// Only sent with requests from the same site
+    Strict,
+    /// Also sent when the user follows a link from another site
+    Lax,
+    /// Sent with every request, browsers require `Secure` with it
+    None,
+}
+
+/// Secret key of signed and encrypted cookies (requires the `secure-cookies` feature).
+/// Cookies written with a key can only be read with the same key, so it must be kept across restarts and shared by every server
+/// of an application.
+#[cfg(feature = "secure-cookies")]
+#[derive(Clone)]
+pub struct CookieKey {
+    signing: [u8; 32],
+    encryption: [u8; 32],
+}
+
+impl Cookie {
+    /// Create a cookie, panics when `name` is not a valid cookie name (a non-empty token without spaces or separators)
+    pub fn new(name: &str, value: &str) -> Self {
+        assert!(is_token(name), "Invalid cookie name `{}`", name);
+        Self {
+            name: String::from(name),
+            value: String::from(value),
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// A cookie removing the cookie `name` from the client: it is empty and expired.
+    /// Give it the same path and domain as the cookie to remove.
+    pub fn removal(name: &str) -> Self {
+        Self::new(name, "").max_age(Duration::ZERO).expires(UNIX_EPOCH)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// Only send the cookie with requests whose path starts with `path`.
+    /// Without it, browsers use the folder of the request that set the cookie, so `/` is usually wanted.
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(String::from(path));
+        self
+    }
+
+    /// Also send the cookie to the subdomains of `domain`
+    pub fn domain(mut self, domain: &str) -> Self {
+        self.domain = Some(String::from(domain));
+        self
+    }
+
+    /// Keep the cookie for `max_age`, without it (and without `expires()`) the cookie is removed when the browser is closed
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Keep the cookie until `expires`, `max_age()` takes precedence over it
+    pub fn expires(mut self, expires: SystemTime) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+
+    /// Only send the cookie over HTTPS
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Hide the cookie from JavaScript
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    /// Sign the value with `key`, read it back with `MoreDetailsRequest::signed_cookie()`.
+    /// The value stays readable by the client.
+    #[cfg(feature = "secure-cookies")]
+    pub fn signed(mut self, key: &CookieKey) -> Self {
+        let tag = key.mac(&self.name, &self.value).finalize().into_bytes();
+        self.value = format!("{}.{}", self.value, URL_SAFE_NO_PAD.encode(tag));
+        self
+    }
+
+    /// Encrypt the value with `key`, read it back with `MoreDetailsRequest::private_cookie()`
+    #[cfg(feature = "secure-cookies")]
+    pub fn encrypted(mut self, key: &CookieKey) -> Self {
+        let nonce = random::bytes(NONCE_SIZE);
+        let payload = Payload {
+            msg: self.value.as_bytes(),
+            aad: self.name.as_bytes(),
+        };
+        let sealed = key.cipher().encrypt(Nonce::from_slice(&nonce), payload).expect("ChaCha20-Poly1305 encryption failed");
+        self.value = URL_SAFE_NO_PAD.encode([nonce, sealed].concat());
+        self
+    }
+}
+
+/// The value of the `Set-Cookie` header
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, percent::encode(&self.value))?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", attribute_value(path))?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", attribute_value(domain))?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", http_date(expires))?;
+        }
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={}", same_site)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SameSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SameSite::Strict => write!(f, "Strict"),
+            SameSite::Lax => write!(f, "Lax"),
+            SameSite::None => write!(f, "None"),
+        }
+    }
+}
+
+#[cfg(feature = "secure-cookies")]
+impl CookieKey {
+    /// Derive the key from a secret of at least 32 random bytes, panics when it is shorter
+    pub fn from_secret(secret: &[u8]) -> Self {
+        assert!(secret.len() >= 32, "A cookie secret must have at least 32 bytes");
+        let derive = |purpose: &[u8]| -> [u8; 32] {
+            let mut mac = <HmacSha256 as Mac>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+            mac.update(purpose);
+            mac.finalize().into_bytes().into()
+        };
+        Self {
+            signing: derive(b"speed-rs cookie signing"),
+            encryption: derive(b"speed-rs cookie encryption"),
+        }
+    }
+
+    /// Generate a random key from the random number generator of the operating system, cookies written with it cannot be read after a restart.
+    /// Panics when the operating system cannot provide random bytes.
+    pub fn generate() -> Self {
+        Self::from_secret(&random::bytes(64))
+    }
+
+    /// Verify a value written by `Cookie::signed()` for the cookie `name`, returns the original value
+    pub(crate) fn verify(&self, name: &str, value: &str) -> Option<String> {
+        let (value, tag) = value.rsplit_once('.')?;
+        let tag = URL_SAFE_NO_PAD.decode(tag).ok()?;
+        // constant time comparison
+        self.mac(name, value).verify_slice(&tag).ok()?;
+        Some(String::from(value))
+    }
+
+    /// Decrypt a value written by `Cookie::encrypted()` for the cookie `name`
+    pub(crate) fn decrypt(&self, name: &str, value: &str) -> Option<String> {
+        let data = URL_SAFE_NO_PAD.decode(value).ok()?;
+        if data.len() < NONCE_SIZE {
+            return None;
+        }
+        let (nonce, sealed) = data.split_at(NONCE_SIZE);
+        let payload = Payload {
+            msg: sealed,
+            aad: name.as_bytes(),
+        };
+        let plain = self.cipher().decrypt(Nonce::from_slice(nonce), payload).ok()?;
+        String::from_utf8(plain).ok()
+    }
+
+    /** The MAC of `name=value`, the name is signed too so a value cannot be moved to another cookie */
+    fn mac(&self, name: &str, value: &str) -> HmacSha256 {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.signing).expect("HMAC accepts keys of any size");
+        mac.update(name.as_bytes());
+        mac.update(b"=");
+        mac.update(value.as_bytes());
+        mac
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(&self.encryption))
+    }
+}
+
+/// The cookies of a `Cookie` request header (`a=1; b=2`), in order. Values are percent-decoded and unquoted.
+pub(crate) fn parse_cookie_header(header: &str) -> Vec<(String, String)> {
+    header
+        .split(';')
+        .filter_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            let name = name.trim();
+            if name.is_empty() {
+                return None;
+            }
+            let value = value.trim();
+            let value = value.strip_prefix('"').and_then(|value| value.strip_suffix('"')).unwrap_or(value);
+            Some((String::from(name), percent::decode(value)))
+        })
+        .collect()
+}
+
+/// Whether `name` is a token of RFC 7230, which cookie names must be
+fn is_token(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// An attribute value without the characters that would end it or the header
+fn attribute_value(value: &str) -> String {
+    value.chars().filter(|c| *c != ';' && !c.is_control()).collect()
+}
+
+/// Format `time` as an HTTP date (`Wed, 21 Oct 2015 07:28:00 GMT`)
+fn http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs());
+    let days = secs / 86400;
+    let secs_of_day = secs % 86400;
+
+    // civil date from the number of days since 1970-01-01 (H. Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_cookie_attributes() {
+        let cookie = Cookie::new("id", "a b;c\r\n")
+            .path("/a;b")
+            .domain("example.com")
+            .max_age(Duration::from_secs(3600))
+            .expires(UNIX_EPOCH + Duration::from_secs(1445412480))
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::Strict);
+        assert_eq!(
+            cookie.to_string(),
+            "id=a%20b%3Bc%0D%0A; Path=/ab; Domain=example.com; Max-Age=3600; Expires=Wed, 21 Oct 2015 07:28:00 GMT; Secure; HttpOnly; SameSite=Strict"
+        );
+        assert_eq!(Cookie::new("a", "b").to_string(), "a=b");
+        assert_eq!(Cookie::removal("old").to_string(), "old=; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT");
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid cookie name")]
+    fn invalid_names_panic() {
+        Cookie::new("a b", "c");
+    }
+
+    #[test]
+    fn http_dates() {
+        let date = |secs: u64| http_date(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(date(951782400), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(date(4107542399), "Sun, 28 Feb 2100 23:59:59 GMT");
+        assert_eq!(date(4107542400), "Mon, 01 Mar 2100 00:00:00 GMT");
+        assert_eq!(http_date(UNIX_EPOCH - Duration::from_secs(1)), "Thu, 01 Jan 1970 00:00:00 GMT");
+    }
+
+    #[test]
+    fn parses_cookie_headers() {
+        let cookies = parse_cookie_header("a=1; b = \"x y\" ;c=%E2%82%AC%3B; =skipped; novalue; d=");
+        let expected = [("a", "1"), ("b", "x y"), ("c", "\u{20ac};"), ("d", "")];
+        assert_eq!(cookies, expected.map(|(name, value)| (String::from(name), String::from(value))));
+        assert!(parse_cookie_header("").is_empty());
+    }
+
+    #[test]
+    fn encoded_values_decode_back() {
+        let value = "caf\u{e9} = 1; 2, \"3\"";
+        let header = Cookie::new("v", value).to_string();
+        assert_eq!(parse_cookie_header(&header), vec![(String::from("v"), String::from(value))]);
+    }
+
+    #[cfg(feature = "secure-cookies")]
+    mod secure {
+        use super::*;
+
+        fn key(byte: u8) -> CookieKey {
+            CookieKey::from_secret(&[byte; 32])
+        }
+
+        #[test]
+        fn signed_values_verify() {
+            let cookie = Cookie::new("user", "42.admin").signed(&key(1));
+            assert!(cookie.value().starts_with("42.admin."));
+            assert_eq!(key(1).verify("user", cookie.value()), Some(String::from("42.admin")));
+            assert_eq!(key(2).verify("user", cookie.value()), None);
+        }
+
+        #[test]
+        fn tampered_signed_values_are_rejected() {
+            let signed = Cookie::new("user", "42").signed(&key(1));
+            let tag = signed.value().strip_prefix("42.").unwrap();
+            assert_eq!(key(1).verify("user", &format!("43.{}", tag)), None);
+            assert_eq!(key(1).verify("user", "42"), None);
+            assert_eq!(key(1).verify("user", "42.not base64!"), None);
+            assert_eq!(key(1).verify("user", &format!("42.{}", &tag[1..])), None);
+        }
+
+        #[test]
+        fn signed_values_are_bound_to_their_name() {
+            let signed = Cookie::new("user", "42").signed(&key(1));
+            assert_eq!(key(1).verify("admin", signed.value()), None);
+        }
+
+        #[test]
+        fn encrypted_values_decrypt() {
+            let first = Cookie::new("cart", "3 items").encrypted(&key(1));
+            let second = Cookie::new("cart", "3 items").encrypted(&key(1));
+            assert!(!first.value().contains("items"));
+            // every encryption has its own nonce
+            assert_ne!(first.value(), second.value());
+            assert_eq!(key(1).decrypt("cart", first.value()), Some(String::from("3 items")));
+            assert_eq!(key(1).decrypt("cart", second.value()), Some(String::from("3 items")));
+        }
+
+        #[test]
+        fn encrypted_values_need_the_key_and_name() {
+            let encrypted = Cookie::new("cart", "3 items").encrypted(&key(1));
+            assert_eq!(key(2).decrypt("cart", encrypted.value()), None);
+            assert_eq!(key(1).decrypt("other", encrypted.value()), None);
+
+            let mut data = URL_SAFE_NO_PAD.decode(encrypted.value()).unwrap();
+            let last = data.len() - 1;
+            data[last] ^= 1;
+            assert_eq!(key(1).decrypt("cart", &URL_SAFE_NO_PAD.encode(&data)), None);
+            assert_eq!(key(1).decrypt("cart", &URL_SAFE_NO_PAD.encode(&data[..NONCE_SIZE - 1])), None);
+            assert_eq!(key(1).decrypt("cart", "%%%"), None);
+        }
+
+        #[test]
+        fn keys_derive_from_the_secret() {
+            assert_eq!(key(1).signing, key(1).signing);
+            assert_ne!(key(1).signing, key(1).encryption);
+            assert_ne!(CookieKey::generate().signing, CookieKey::generate().signing);
+        }
+
+        #[test]
+        #[should_panic(expected = "at least 32 bytes")]
+        fn short_secrets_panic() {
+            CookieKey::from_secret(&[0; 31]);
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use std::{collections::HashMap, fmt, vec};
+
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, SeqAccess, Visitor};
+
+use super::ExtractError;
+
+/// Deserialize `T` from key - value pairs such as a parsed query string.
+/// A repeated key fills a sequence field, and the last value is used for other fields.
+pub(crate) fn from_pairs<T: DeserializeOwned>(pairs: Vec<(String, String)>) -> Result<T, ExtractError> {
+    T::deserialize(PairsDeserializer {
+        fields: group(pairs).into_iter(),
+        values: None,
+    })
+}
+
+/// Values of each key in the order of the pairs, the keys keep the order of their first pair
+fn group(pairs: Vec<(String, String)>) -> Vec<(String, Vec<String>)> {
+    // `positions` finds the field of a key without a scan
+    let mut fields = Vec::<(String, Vec<String>)>::new();
+    let mut positions = HashMap::<String, usize>::new();
+    for (key, value) in pairs {
+        match positions.get(&key) {
+            Some(position) => fields[*position].1.push(value),
+            None => {
+                positions.insert(key.clone(), fields.len());
+                fields.push((key, vec![value]));
+            }
+        }
+    }
+    fields
+}
+
+impl de::Error for ExtractError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        ExtractError::Invalid {
+            field: None,
+            message: msg.to_string(),
+        }
+    }
+
+    fn missing_field(field: &'static str) -> Self {
+        ExtractError::Invalid {
+            field: Some(String::from(field)),
+            message: String::from("missing"),
+        }
+    }
+}
+
+impl ExtractError {
+    /** Attach the field name to an error raised while reading its value */
+    fn in_field(self, name: &str) -> Self {
+        match self {
+            ExtractError::Invalid { field: None, message } => ExtractError::Invalid {
+                field: Some(String::from(name)),
+                message,
+            },
+            e => e,
+        }
+    }
+}
+
+/// The whole set of pairs, seen as a map
+struct PairsDeserializer {
+    fields: vec::IntoIter<(String, Vec<String>)>,
+    values: Option<(String, Vec<String>)>,
+}
+
+/// The values of one key
+struct ValueDeserializer {
+    values: Vec<String>,
+}
+
+/// The values of a repeated key, seen as a sequence
+struct ValuesSeq {
+    values: vec::IntoIter<String>,
+}
+
+impl<'de> de::Deserializer<'de> for PairsDeserializer {
+    type Error = ExtractError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_map(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+impl<'de> MapAccess<'de> for PairsDeserializer {
+    type Error = ExtractError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error> {
+        match self.fields.next() {
+            Some((key, values)) => {
+                let deserializer: de::value::StrDeserializer<ExtractError> = key.as_str().into_deserializer();
+                let name = seed.deserialize(deserializer)?;
+                self.values = Some((key, values));
+                Ok(Some(name))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Self::Error> {
+        let (key, values) = self.values.take().ok_or_else(|| de::Error::custom("value without a key"))?;
+        seed.deserialize(ValueDeserializer { values }).map_err(|e| e.in_field(&key))
+    }
+}
+
+impl ValueDeserializer {
+    fn last(&self) -> &str {
+        self.values.last().map_or("", |value| value.as_str())
+    }
+
+    fn parse<T>(&self) -> Result<T, ExtractError>
+            where T: std::str::FromStr, T::Err: fmt::Display {
+        let value = self.last();
+        value.parse().map_err(|e| de::Error::custom(format!("invalid value `{}`: {}", value, e)))
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                visitor.$visit(self.parse()?)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for ValueDeserializer {
+    type Error = ExtractError;
+
+    fn deserialize_any<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_string(self.values.pop().unwrap_or_default())
+    }
+
+    deserialize_parsed! {
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_i128 => visit_i128,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_u128 => visit_u128,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    /// Besides `true` and `false`, accept the values sent by HTML checkboxes and the usual `1` and `0`
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.last() {
+            "true" | "on" | "1" => visitor.visit_bool(true),
+            "false" | "off" | "0" => visitor.visit_bool(false),
+            value => Err(de::Error::custom(format!("invalid value `{}`: expected a boolean", value))),
+        }
+    }
+
+    /// An empty value (an empty form input) is `None`
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        if self.last().is_empty() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_seq(ValuesSeq {
+            values: self.values.into_iter(),
+        })
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(mut self, _name: &'static str, _variants: &'static [&'static str], visitor: V) -> Result<V::Value, Self::Error> {
+        let value: de::value::StringDeserializer<ExtractError> = self.values.pop().unwrap_or_default().into_deserializer();
+        visitor.visit_enum(value)
+    }
+
+    serde::forward_to_deserialize_any! {
+        str string bytes byte_buf unit unit_struct tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de> SeqAccess<'de> for ValuesSeq {
+    type Error = ExtractError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Self::Error> {
+        match self.values.next() {
+            Some(value) => seed.deserialize(ValueDeserializer { values: vec![value] }).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    fn pairs(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter().map(|(key, value)| (String::from(*key), String::from(*value))).collect()
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Search {
+        q: String,
+        page: Option<u32>,
+        #[serde(default)]
+        tag: Vec<String>,
+        exact: Option<bool>,
+    }
+
+    #[test]
+    fn repeated_keys_fill_sequences() {
+        let search: Search = from_pairs(pairs(&[("tag", "a"), ("q", "rust"), ("tag", "b"), ("page", "2"), ("exact", "on")])).unwrap();
+        assert_eq!(
+            search,
+            Search {
+                q: String::from("rust"),
+                page: Some(2),
+                tag: vec![String::from("a"), String::from("b")],
+                exact: Some(true),
+            }
+        );
+    }
+
+    #[test]
+    fn last_value_wins_and_empty_is_none() {
+        let search: Search = from_pairs(pairs(&[("q", "a"), ("q", "b"), ("page", "")])).unwrap();
+        assert_eq!(search.q, "b");
+        assert_eq!(search.page, None);
+    }
+
+    #[test]
+    fn errors_name_the_field() {
+        let e = from_pairs::<Search>(pairs(&[("q", "a"), ("page", "abc")])).unwrap_err();
+        assert!(matches!(&e, ExtractError::Invalid { field: Some(field), .. } if field == "page"), "{}", e);
+        let e = from_pairs::<Search>(pairs(&[("page", "1")])).unwrap_err();
+        assert!(matches!(&e, ExtractError::Invalid { field: Some(field), .. } if field == "q"), "{}", e);
+    }
+
+    #[test]
+    fn repeated_keys_are_grouped_in_first_seen_order() {
+        let fields = group(pairs(&[("b", "1"), ("a", "2"), ("b", "3"), ("c", ""), ("a", "4"), ("b", "5")]));
+        let expected = vec![
+            (String::from("b"), vec![String::from("1"), String::from("3"), String::from("5")]),
+            (String::from("a"), vec![String::from("2"), String::from("4")]),
+            (String::from("c"), vec![String::new()]),
+        ];
+        assert_eq!(fields, expected);
+        assert!(group(Vec::new()).is_empty());
+    }
+}
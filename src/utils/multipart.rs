@@ -0,0 +1,655 @@
+//! `multipart/form-data` parsing (RFC 7578) for form submissions with file uploads.
+//!
+//! The parser reads the parts one by one from any `BufRead`. Part contents larger than `MultipartLimits::memory_limit` are
+//! written to a temporary file instead of memory, which is removed when the part is dropped unless it is saved.
+//!
+//! `Multipart::from_request()` parses the body from `HttpRequest::body_reader()`. The server writes request bodies larger than
+//! `HttpServerConfig::max_body_in_memory` to a temporary file while it receives them, so a large upload is never held in memory:
+//! it goes from the connection to that file, and its large parts from there to their own temporary files. Uploads are still
+//! bounded by `HttpServerConfig::max_body_size`, which can be raised for them.
+//!
+//! Example:
+//! ```rust,ignore
+//! use speed_rs_core::utils::multipart::{Multipart, MultipartLimits};
+//!
+//! server.insert_handler(|req, mut res| {
+//!     let mut multipart = match Multipart::from_request(&req, MultipartLimits::default()) {
+//!         Ok(multipart) => multipart,
+//!         Err(e) => return Err((req, res, Box::new(e))),
+//!     };
+//!     loop {
+//!         match multipart.next_part() {
+//!             Ok(Some(part)) if part.is_file() => {
+//!                 println!("{:?} {:?} {} bytes", part.filename(), part.content_type(), part.size());
+//!                 part.save("uploads/avatar.png").unwrap();
+//!             }
+//!             Ok(Some(part)) => println!("{:?} = {:?}", part.name(), part.text()),
+//!             Ok(None) => break,
+//!             Err(e) => return Err((req, res, Box::new(e))),
+//!         }
+//!     }
+//!     res.set_status(HttpStatusStruct(204, "No Content"));
+//!     Ok((req, res))
+//! });
+//! ```
+
+use std::{
+    env,
+    error::Error,
+    fmt,
+    fs::{self, File},
+    io::{self, BufRead, Cursor, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{parser::parse_header_line, BodyReader, HeaderMap, HttpRequest, HttpStatusStruct};
+pub use crate::temp_file::TempFile;
+
+use super::percent;
+
+/// Bytes read from the source at once
+const READ_SIZE: usize = 8 * 1024;
+
+/// Longest boundary allowed by RFC 2046 section 5.1.1
+const MAX_BOUNDARY_LENGTH: usize = 70;
+
+/// Limits applied while parsing a multipart body.
+///
+/// Example:
+/// ```rust,ignore
+/// let mut limits = MultipartLimits::default();
+/// limits.max_part_size = Some(50 * 1024 * 1024);
+/// limits.temp_dir = PathBuf::from("/var/tmp/uploads");
+/// ```
+#[derive(Clone, Debug)]
+pub struct MultipartLimits {
+    /// Maximum size in bytes of the content of one part. Default is 10 MiB, `None` is unlimited.
+    pub max_part_size: Option<u64>,
+    /// Maximum size in bytes of the whole multipart body, boundaries and part headers included. Default is `None` (unlimited).
+    pub max_total_size: Option<u64>,
+    /// Maximum number of parts. Default is 100.
+    pub max_parts: usize,
+    /// Maximum size in bytes of the header section of one part. Default is 8 KiB.
+    pub max_header_size: usize,
+    /// Part contents larger than this many bytes are written to a temporary file. Default is 64 KiB.
+    pub memory_limit: usize,
+    /// Folder of the temporary files. Default is `std::env::temp_dir()`.
+    pub temp_dir: PathBuf,
+}
+
+/// Why a multipart body could not be parsed
+#[derive(Debug)]
+pub enum MultipartError {
+    /// The request is not `multipart/*` or has no valid boundary
+    NotMultipart,
+    /// The body does not follow the multipart format
+    Invalid(&'static str),
+    /// A part is larger than `MultipartLimits::max_part_size` or its headers are larger than `MultipartLimits::max_header_size`
+    PartTooLarge,
+    /// The body is larger than `MultipartLimits::max_total_size`
+    TooLarge,
+    /// The body has more than `MultipartLimits::max_parts` parts
+    TooManyParts,
+    /// Reading the body or writing a temporary file failed
+    Io(io::Error),
+}
+
+/// Parser of a multipart body, see the module documentation
+pub struct Multipart<R> {
+    reader: R,
+    limits: MultipartLimits,
+    /// `CRLF--boundary`, the CRLF belongs to the delimiter and not to the content before it
+    delimiter: Vec<u8>,
+    /// Bytes read from `reader` and not parsed yet
+    buf: Vec<u8>,
+    eof: bool,
+    total: u64,
+    parts: usize,
+    state: State,
+}
+
+enum State {
+    Preamble,
+    Parts,
+    Done,
+}
+
+/// One part of a multipart body
+pub struct Part {
+    headers: HeaderMap,
+    name: Option<String>,
+    filename: Option<String>,
+    size: u64,
+    data: PartData,
+}
+
+/// Content of a part
+pub enum PartData {
+    Memory(Vec<u8>),
+    File(TempFile),
+}
+
+/// Destination of the bytes of a part
+enum Sink {
+    Discard,
+    Part {
+        memory: Vec<u8>,
+        file: Option<(TempFile, File)>,
+        size: u64,
+        max_size: Option<u64>,
+        memory_limit: usize,
+        temp_dir: PathBuf,
+    },
+}
+
+impl Default for MultipartLimits {
+    fn default() -> Self {
+        Self {
+            max_part_size: Some(10 * 1024 * 1024),
+            max_total_size: None,
+            max_parts: 100,
+            max_header_size: 8 * 1024,
+            memory_limit: 64 * 1024,
+            temp_dir: env::temp_dir(),
+        }
+    }
+}
+
+impl<'a> Multipart<BodyReader<'a>> {
+    /// Parse the body of `req` from `HttpRequest::body_reader()`, the boundary is taken from its `Content-Type` header
+    pub fn from_request(req: &'a HttpRequest, limits: MultipartLimits) -> Result<Multipart<BodyReader<'a>>, MultipartError> {
+        let content_type = req.headers().get("Content-Type").ok_or(MultipartError::NotMultipart)?;
+        let boundary = boundary(content_type).ok_or(MultipartError::NotMultipart)?;
+        Ok(Multipart::new(req.body_reader()?, &boundary, limits))
+    }
+}
+
+impl<R: BufRead> Multipart<R> {
+    /// Parse the multipart body read from `reader`, the parts are separated by `boundary`
+    pub fn new(reader: R, boundary: &str, limits: MultipartLimits) -> Self {
+        Self {
+            reader,
+            limits,
+            delimiter: [b"\r\n--", boundary.as_bytes()].concat(),
+            // the first delimiter is not preceded by a line break when there is no preamble
+            buf: Vec::from(&b"\r\n"[..]),
+            eof: false,
+            total: 0,
+            parts: 0,
+            state: State::Preamble,
+        }
+    }
+
+    /// Read the next part, returns `None` after the last one
+    pub fn next_part(&mut self) -> Result<Option<Part>, MultipartError> {
+        match self.state {
+            State::Done => return Ok(None),
+            State::Preamble => {
+                self.copy_until_delimiter(&mut Sink::Discard)?;
+                self.state = State::Parts;
+            }
+            State::Parts => {}
+        }
+
+        // `--` after the delimiter closes the body, otherwise a line break starts the next part
+        while self.buf.len() < 2 {
+            if !self.fill()? {
+                return Err(MultipartError::Invalid("unexpected end of body"));
+            }
+        }
+        if self.buf.starts_with(b"--") {
+            self.state = State::Done;
+            return Ok(None);
+        }
+        let padding = self.read_line(self.limits.max_header_size)?;
+        if !padding.iter().all(|b| *b == b' ' || *b == b'\t') {
+            return Err(MultipartError::Invalid("unexpected data after a boundary"));
+        }
+
+        self.parts += 1;
+        if self.parts > self.limits.max_parts {
+            return Err(MultipartError::TooManyParts);
+        }
+
+        let mut headers = HeaderMap::new();
+        let mut header_size: usize = 0;
+        loop {
+            let line = self.read_line(self.limits.max_header_size.saturating_sub(header_size))?;
+            if line.is_empty() {
+                break;
+            }
+            header_size += line.len();
+            let (name, value) = parse_header_line(&String::from_utf8_lossy(&line)).ok_or(MultipartError::Invalid("invalid part header"))?;
+            headers.append(name, value);
+        }
+        let (name, filename) = match headers.get("Content-Disposition") {
+            Some(value) => content_disposition(value),
+            None => (None, None),
+        };
+
+        let mut sink = Sink::Part {
+            memory: Vec::new(),
+            file: None,
+            size: 0,
+            max_size: self.limits.max_part_size,
+            memory_limit: self.limits.memory_limit,
+            temp_dir: self.limits.temp_dir.clone(),
+        };
+        self.copy_until_delimiter(&mut sink)?;
+        let (size, data) = sink.finish()?;
+
+        Ok(Some(Part {
+            headers,
+            name,
+            filename,
+            size,
+            data,
+        }))
+    }
+
+    /** Read more bytes from the source, returns `false` at its end */
+    fn fill(&mut self) -> Result<bool, MultipartError> {
+        if self.eof {
+            return Ok(false);
+        }
+        let available = self.reader.fill_buf()?;
+        let size = available.len().min(READ_SIZE);
+        if size == 0 {
+            self.eof = true;
+            return Ok(false);
+        }
+        self.buf.extend_from_slice(&available[..size]);
+        self.reader.consume(size);
+
+        self.total += size as u64;
+        if self.limits.max_total_size.is_some_and(|max| self.total > max) {
+            return Err(MultipartError::TooLarge);
+        }
+        Ok(true)
+    }
+
+    /** Read a CRLF terminated line of at most `limit` bytes, without the CRLF */
+    fn read_line(&mut self, limit: usize) -> Result<Vec<u8>, MultipartError> {
+        let mut searched = 0;
+        loop {
+            if let Some(index) = find(&self.buf[searched..], b"\r\n").map(|index| index + searched) {
+                if index > limit {
+                    return Err(MultipartError::PartTooLarge);
+                }
+                let line = self.buf[..index].to_vec();
+                self.buf.drain(..index + 2);
+                return Ok(line);
+            }
+            if self.buf.len() > limit + 1 {
+                return Err(MultipartError::PartTooLarge);
+            }
+            searched = self.buf.len().saturating_sub(1);
+            if !self.fill()? {
+                return Err(MultipartError::Invalid("unexpected end of body"));
+            }
+        }
+    }
+
+    /** Move the bytes before the next delimiter to `sink` and consume the delimiter */
+    fn copy_until_delimiter(&mut self, sink: &mut Sink) -> Result<(), MultipartError> {
+        loop {
+            if let Some(index) = find(&self.buf, &self.delimiter) {
+                sink.write(&self.buf[..index])?;
+                self.buf.drain(..index + self.delimiter.len());
+                return Ok(());
+            }
+            // the end of the buffer may be the start of the delimiter
+            let keep = self.delimiter.len() - 1;
+            if self.buf.len() > keep {
+                let size = self.buf.len() - keep;
+                sink.write(&self.buf[..size])?;
+                self.buf.drain(..size);
+            }
+            if !self.fill()? {
+                return Err(MultipartError::Invalid("missing closing boundary"));
+            }
+        }
+    }
+}
+
+impl Sink {
+    fn write(&mut self, data: &[u8]) -> Result<(), MultipartError> {
+        let Sink::Part { memory, file, size, max_size, memory_limit, temp_dir } = self else {
+            return Ok(());
+        };
+
+        *size += data.len() as u64;
+        if max_size.is_some_and(|max| *size > max) {
+            return Err(MultipartError::PartTooLarge);
+        }
+        if file.is_none() && memory.len() + data.len() > *memory_limit {
+            let (temp_file, mut handle) = TempFile::create(temp_dir, "speed-rs-upload")?;
+            handle.write_all(memory)?;
+            memory.clear();
+            *file = Some((temp_file, handle));
+        }
+        match file {
+            Some((_, handle)) => handle.write_all(data)?,
+            None => memory.extend_from_slice(data),
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(u64, PartData), MultipartError> {
+        match self {
+            Sink::Discard => Ok((0, PartData::Memory(Vec::new()))),
+            Sink::Part { memory, file, size, .. } => match file {
+                Some((temp_file, mut handle)) => {
+                    handle.flush()?;
+                    Ok((size, PartData::File(temp_file)))
+                }
+                None => Ok((size, PartData::Memory(memory))),
+            },
+        }
+    }
+}
+
+impl Part {
+    /// Retrieve the headers of the part
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// Name of the form field, from `Content-Disposition`
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Name of the uploaded file as sent by the client, from `Content-Disposition`.
+    /// Do not use it as a path without sanitizing it.
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    /// `Content-Type` of the part, form fields usually have none (`text/plain` is implied)
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.get("Content-Type")
+    }
+
+    /// Whether the part is a file upload (it has a file name)
+    pub fn is_file(&self) -> bool {
+        self.filename.is_some()
+    }
+
+    /// Size in bytes of the content
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Retrieve the content, in memory or in a temporary file
+    pub fn data(&self) -> &PartData {
+        &self.data
+    }
+
+    pub fn into_data(self) -> PartData {
+        self.data
+    }
+
+    /// Read the content without loading a temporary file in memory
+    pub fn reader(&self) -> io::Result<Box<dyn Read + '_>> {
+        match &self.data {
+            PartData::Memory(data) => Ok(Box::new(Cursor::new(data.as_slice()))),
+            PartData::File(file) => Ok(Box::new(File::open(file.path())?)),
+        }
+    }
+
+    /// Retrieve the content in memory
+    pub fn bytes(&self) -> io::Result<Vec<u8>> {
+        match &self.data {
+            PartData::Memory(data) => Ok(data.clone()),
+            PartData::File(file) => fs::read(file.path()),
+        }
+    }
+
+    /// Retrieve the content as text, invalid UTF-8 is replaced
+    pub fn text(&self) -> io::Result<String> {
+        Ok(String::from_utf8_lossy(&self.bytes()?).into_owned())
+    }
+
+    /// Write the content to `path`, a temporary file is moved there
+    pub fn save<P: AsRef<Path>>(self, path: P) -> io::Result<()> {
+        match self.data {
+            PartData::Memory(data) => fs::write(path, data),
+            PartData::File(file) => file.persist(path),
+        }
+    }
+}
+
+impl MultipartError {
+    /// Status of the response to a request with this error
+    pub fn status(&self) -> HttpStatusStruct {
+        match self {
+            MultipartError::NotMultipart => HttpStatusStruct(415, "Unsupported Media Type"),
+            MultipartError::Invalid(_) => HttpStatusStruct(400, "Bad Request"),
+            MultipartError::PartTooLarge | MultipartError::TooLarge | MultipartError::TooManyParts => HttpStatusStruct(413, "Content Too Large"),
+            MultipartError::Io(_) => HttpStatusStruct(500, "Interal Server Error"),
+        }
+    }
+}
+
+impl fmt::Display for MultipartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MultipartError::NotMultipart => write!(f, "Expected a multipart request body with a boundary"),
+            MultipartError::Invalid(reason) => write!(f, "Invalid multipart body: {}", reason),
+            MultipartError::PartTooLarge => write!(f, "A part of the multipart body is too large"),
+            MultipartError::TooLarge => write!(f, "The multipart body is too large"),
+            MultipartError::TooManyParts => write!(f, "The multipart body has too many parts"),
+            MultipartError::Io(e) => write!(f, "Failed to read the multipart body: {}", e),
+        }
+    }
+}
+
+impl Error for MultipartError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MultipartError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MultipartError {
+    fn from(e: io::Error) -> Self {
+        MultipartError::Io(e)
+    }
+}
+
+/// Position of the first occurrence of `needle` in `haystack`
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Boundary parameter of a `multipart/*` media type
+fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    let media_type = params.next()?.trim();
+    if !media_type.to_ascii_lowercase().starts_with("multipart/") {
+        return None;
+    }
+    let boundary = params.find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        key.trim().eq_ignore_ascii_case("boundary").then(|| value.trim().trim_matches('"'))
+    })?;
+    if boundary.is_empty() || boundary.len() > MAX_BOUNDARY_LENGTH {
+        return None;
+    }
+    Some(String::from(boundary))
+}
+
+/// Field name and file name of a `Content-Disposition: form-data; name="..."; filename="..."` header.
+/// Browsers percent-encode quotes in the values, so a backslash is an ordinary character (RFC 7578 section 4.2).
+fn content_disposition(value: &str) -> (Option<String>, Option<String>) {
+    let mut name = None;
+    let mut filename = None;
+    let mut extended_filename = None;
+    for (key, value) in disposition_params(value) {
+        match key.to_ascii_lowercase().as_str() {
+            "name" => name = Some(value),
+            "filename" => filename = Some(value),
+            // filename*=UTF-8''%E2%82%AC%20rates.txt (RFC 5987)
+            "filename*" => extended_filename = value.splitn(3, '\'').nth(2).map(percent::decode),
+            _ => {}
+        }
+    }
+    (name, extended_filename.or(filename))
+}
+
+/// The `key=value` parameters after the disposition type, values can be quoted
+fn disposition_params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut chars = value.chars().peekable();
+    for c in chars.by_ref() {
+        if c == ';' {
+            break;
+        }
+    }
+
+    while chars.peek().is_some() {
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|c| *c != '=' && *c != ';') {
+            key.push(c);
+        }
+        let mut value = String::new();
+        if chars.next() == Some('=') {
+            while chars.next_if_eq(&' ').is_some() {}
+            if chars.next_if_eq(&'"').is_some() {
+                for c in chars.by_ref() {
+                    if c == '"' {
+                        break;
+                    }
+                    value.push(c);
+                }
+                for c in chars.by_ref() {
+                    if c == ';' {
+                        break;
+                    }
+                }
+            } else {
+                for c in chars.by_ref() {
+                    if c == ';' {
+                        break;
+                    }
+                    value.push(c);
+                }
+                value = String::from(value.trim());
+            }
+        }
+        let key = key.trim();
+        if !key.is_empty() {
+            params.push((String::from(key), value));
+        }
+    }
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+
+    const BODY: &[u8] = b"preamble\r\n\
+        --xyz\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\
+        \r\n\
+        a line\r\n--xy and \r\n-\r\n\
+        --xyz  \r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"a.txt\"; filename*=UTF-8''%E2%82%AC.txt\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        file content\r\n\
+        --xyz--\r\n\
+        epilogue";
+
+    fn limits() -> MultipartLimits {
+        MultipartLimits {
+            memory_limit: 4,
+            ..MultipartLimits::default()
+        }
+    }
+
+    fn parse<R: BufRead>(reader: R, limits: MultipartLimits) -> Result<Vec<Part>, MultipartError> {
+        let mut multipart = Multipart::new(reader, "xyz", limits);
+        let mut parts = Vec::new();
+        while let Some(part) = multipart.next_part()? {
+            parts.push(part);
+        }
+        Ok(parts)
+    }
+
+    #[test]
+    fn delimiter_split_across_reads() {
+        for capacity in 1..=BODY.len() {
+            let parts = parse(BufReader::with_capacity(capacity, BODY), limits()).unwrap();
+            assert_eq!(parts.len(), 2, "capacity {}", capacity);
+            assert_eq!(parts[0].name(), Some("title"));
+            assert_eq!(parts[0].bytes().unwrap(), b"a line\r\n--xy and \r\n-");
+            assert_eq!(parts[1].filename(), Some("\u{20ac}.txt"));
+            assert_eq!(parts[1].content_type(), Some("text/plain"));
+            assert_eq!(parts[1].text().unwrap(), "file content");
+            assert!(matches!(parts[1].data(), PartData::File(_)));
+        }
+    }
+
+    #[test]
+    fn no_preamble() {
+        let body = b"--xyz\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n1\r\n--xyz--";
+        let parts = parse(&body[..], limits()).unwrap();
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].text().unwrap(), "1");
+        assert!(matches!(parts[0].data(), PartData::Memory(_)));
+    }
+
+    #[test]
+    fn truncated_body() {
+        let body = b"--xyz\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n1\r\n--xy";
+        assert!(matches!(parse(&body[..], limits()), Err(MultipartError::Invalid(_))));
+        assert!(matches!(parse(&b"--xyz\r\nbroken header\r\n\r\n"[..], limits()), Err(MultipartError::Invalid(_))));
+    }
+
+    #[test]
+    fn limits_are_enforced() {
+        let part_size = MultipartLimits {
+            max_part_size: Some(11),
+            ..limits()
+        };
+        assert!(matches!(parse(BODY, part_size), Err(MultipartError::PartTooLarge)));
+
+        let header_size = MultipartLimits {
+            max_header_size: 40,
+            ..limits()
+        };
+        assert!(matches!(parse(BODY, header_size), Err(MultipartError::PartTooLarge)));
+
+        let parts = MultipartLimits {
+            max_parts: 1,
+            ..limits()
+        };
+        assert!(matches!(parse(BODY, parts), Err(MultipartError::TooManyParts)));
+
+        let total_size = MultipartLimits {
+            max_total_size: Some(BODY.len() as u64 - 1),
+            ..limits()
+        };
+        assert!(matches!(parse(BODY, total_size), Err(MultipartError::TooLarge)));
+        let total_size = MultipartLimits {
+            max_total_size: Some(BODY.len() as u64),
+            ..limits()
+        };
+        assert_eq!(parse(BODY, total_size).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn boundary_of_content_type() {
+        assert_eq!(boundary("multipart/form-data; boundary=\"a b\""), Some(String::from("a b")));
+        assert_eq!(boundary("Multipart/Form-Data;charset=utf-8;BOUNDARY=xyz"), Some(String::from("xyz")));
+        assert_eq!(boundary("text/plain; boundary=xyz"), None);
+        assert_eq!(boundary("multipart/form-data; boundary="), None);
+        assert_eq!(boundary(&format!("multipart/form-data; boundary={}", "x".repeat(71))), None);
+    }
+}
@@ -0,0 +1,245 @@
+use std::{collections::HashMap, fmt};
+
+use super::percent;
+
+/// One `/` separated part of a route pattern
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Segment {
+    /// Matches exactly this text
+    Static(String),
+    /// `:name` matches one non-empty segment
+    Param(String),
+    /// `:name?` matches one non-empty segment or nothing
+    Optional(String),
+    /// `*name` matches the rest of the path, it must be the last segment
+    Wildcard(String),
+}
+
+/// Parsed route path such as `/users/:id`, `/posts/:page?` or `/files/*rest`
+#[derive(Clone, Debug)]
+pub(crate) struct RoutePattern {
+    segments: Vec<Segment>,
+}
+
+impl Segment {
+    /// Name of the parameter captured by the segment
+    fn name(&self) -> Option<&str> {
+        match self {
+            Segment::Static(_) => None,
+            Segment::Param(name) | Segment::Optional(name) | Segment::Wildcard(name) => Some(name),
+        }
+    }
+}
+
+impl RoutePattern {
+    /// Parse `path`, panics when a parameter has no name or a wildcard is not the last segment
+    pub(crate) fn parse(path: &str) -> Self {
+        let parts: Vec<&str> = split_path(path).collect();
+        let mut segments = Vec::<Segment>::with_capacity(parts.len());
+
+        for (index, part) in parts.iter().enumerate() {
+            let segment = if let Some(name) = part.strip_prefix(':') {
+                match name.strip_suffix('?') {
+                    Some(name) => Segment::Optional(String::from(name)),
+                    None => Segment::Param(String::from(name)),
+                }
+            } else if let Some(name) = part.strip_prefix('*') {
+                assert!(index == parts.len() - 1, "Wildcard `{}` must be the last segment of route `{}`", part, path);
+                Segment::Wildcard(String::from(name))
+            } else {
+                Segment::Static(String::from(*part))
+            };
+
+            if let Segment::Param(name) | Segment::Optional(name) | Segment::Wildcard(name) = &segment {
+                assert!(!name.is_empty(), "Parameter without a name in route `{}`", path);
+            }
+            segments.push(segment);
+        }
+
+        Self { segments }
+    }
+
+    /// Parse the prefix of a mounted router, panics when it has an optional or a wildcard segment
+    pub(crate) fn parse_prefix(prefix: &str) -> Self {
+        let prefix = prefix.trim_end_matches('/');
+        if prefix.is_empty() {
+            return Self { segments: Vec::new() };
+        }
+        let pattern = Self::parse(prefix);
+        assert!(
+            pattern.segments.iter().all(|segment| matches!(segment, Segment::Static(_) | Segment::Param(_))),
+            "Mount prefix `{}` can only have static and `:name` segments",
+            prefix
+        );
+        pattern
+    }
+
+    /// The pattern of this route once its router is mounted under `prefix`.
+    /// The root route `/` of the mounted router matches the prefix itself.
+    pub(crate) fn nest(&self, prefix: &RoutePattern) -> Self {
+        let mut segments = prefix.segments.clone();
+        if self.segments != [Segment::Static(String::new())] {
+            segments.extend(self.segments.iter().cloned());
+        }
+        if segments.is_empty() {
+            segments.push(Segment::Static(String::new()));
+        }
+        Self { segments }
+    }
+
+    /// Whether the pattern has an optional segment
+    pub(crate) fn has_optional(&self) -> bool {
+        self.segments.iter().any(|segment| matches!(segment, Segment::Optional(_)))
+    }
+
+    /// Number of segments
+    pub(crate) fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Match the decoded segments of a path against the pattern, returns the captured parameters
+    pub(crate) fn matches(&self, parts: &[&str]) -> Option<HashMap<String, String>> {
+        let mut params = HashMap::<String, String>::new();
+        if match_segments(&self.segments, parts, &mut params) {
+            Some(params)
+        } else {
+            None
+        }
+    }
+
+    /// Build a path matching the pattern from `params`, the parameters that are not in the pattern make the query string.
+    /// Returns the name of the first parameter of the pattern missing from `params`.
+    pub(crate) fn url(&self, params: &[(&str, &str)]) -> Result<String, String> {
+        let value = |name: &str| params.iter().find(|(key, _)| *key == name).map(|(_, value)| *value);
+        let mut url = String::new();
+        for segment in self.segments.iter() {
+            match segment {
+                Segment::Static(text) => {
+                    url.push('/');
+                    url.push_str(text);
+                }
+                Segment::Param(name) => {
+                    let value = value(name).ok_or_else(|| name.clone())?;
+                    url.push('/');
+                    url.push_str(&percent::encode(value));
+                }
+                Segment::Optional(name) => {
+                    if let Some(value) = value(name) {
+                        url.push('/');
+                        url.push_str(&percent::encode(value));
+                    }
+                }
+                Segment::Wildcard(name) => {
+                    // the slashes of a wildcard value separate segments
+                    let value = value(name).ok_or_else(|| name.clone())?;
+                    let segments: Vec<String> = value.split('/').map(percent::encode).collect();
+                    url.push('/');
+                    url.push_str(&segments.join("/"));
+                }
+            }
+        }
+        if url.is_empty() {
+            url.push('/');
+        }
+
+        let query: Vec<String> = params
+            .iter()
+            .filter(|(key, _)| !self.segments.iter().any(|segment| segment.name() == Some(*key)))
+            .map(|(key, value)| format!("{}={}", percent::encode(key), percent::encode(value)))
+            .collect();
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query.join("&"));
+        }
+        Ok(url)
+    }
+
+    /// The patterns without optional segments matching the same paths: each optional segment is either a `:name` segment or left out.
+    /// Patterns that only differ by parameter names are listed once.
+    pub(crate) fn variants(&self) -> Vec<Vec<Segment>> {
+        let mut variants: Vec<Vec<Segment>> = vec![Vec::new()];
+        for segment in self.segments.iter() {
+            match segment {
+                Segment::Optional(name) => {
+                    let skipped = variants.clone();
+                    for variant in variants.iter_mut() {
+                        variant.push(Segment::Param(name.clone()));
+                    }
+                    variants.extend(skipped);
+                }
+                segment => {
+                    for variant in variants.iter_mut() {
+                        variant.push(segment.clone());
+                    }
+                }
+            }
+        }
+
+        let mut distinct: Vec<Vec<Segment>> = Vec::with_capacity(variants.len());
+        for variant in variants {
+            if !distinct.iter().any(|other| same_shape(other, &variant)) {
+                distinct.push(variant);
+            }
+        }
+        distinct
+    }
+}
+
+impl fmt::Display for RoutePattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for segment in self.segments.iter() {
+            match segment {
+                Segment::Static(text) => write!(f, "/{}", text)?,
+                Segment::Param(name) => write!(f, "/:{}", name)?,
+                Segment::Optional(name) => write!(f, "/:{}?", name)?,
+                Segment::Wildcard(name) => write!(f, "/*{}", name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Split a path into its segments, the leading `/` is ignored
+pub(crate) fn split_path(path: &str) -> std::str::Split<'_, char> {
+    path.strip_prefix('/').unwrap_or(path).split('/')
+}
+
+/// Whether two patterns match the same paths, parameter names aside
+fn same_shape(a: &[Segment], b: &[Segment]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|pair| match pair {
+        (Segment::Static(a), Segment::Static(b)) => a == b,
+        (Segment::Param(_), Segment::Param(_)) | (Segment::Wildcard(_), Segment::Wildcard(_)) => true,
+        _ => false,
+    })
+}
+
+fn match_segments(segments: &[Segment], parts: &[&str], params: &mut HashMap<String, String>) -> bool {
+    let (segment, rest) = match segments.split_first() {
+        Some(first) => first,
+        None => return parts.is_empty(),
+    };
+
+    match segment {
+        Segment::Static(text) => parts.first() == Some(&text.as_str()) && match_segments(rest, &parts[1..], params),
+        Segment::Param(name) => match parts.first() {
+            Some(part) if !part.is_empty() && match_segments(rest, &parts[1..], params) => {
+                params.insert(name.clone(), String::from(*part));
+                true
+            }
+            _ => false,
+        },
+        Segment::Optional(name) => {
+            if let Some(part) = parts.first() {
+                if !part.is_empty() && match_segments(rest, &parts[1..], params) {
+                    params.insert(name.clone(), String::from(*part));
+                    return true;
+                }
+            }
+            match_segments(rest, parts, params)
+        }
+        Segment::Wildcard(name) => {
+            params.insert(name.clone(), parts.join("/"));
+            true
+        }
+    }
+}
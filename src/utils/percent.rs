@@ -0,0 +1,52 @@
+/// Percent-encode `text` so it can be used as a path segment or a query string key or value.
+/// Only the unreserved characters of RFC 3986 are left as they are.
+pub(crate) fn encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for b in text.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+/// Decode the `%XX` sequences of `text`. Invalid sequences are kept as they are and invalid UTF-8 is replaced.
+pub(crate) fn decode(text: &str) -> String {
+    decode_bytes(text, false)
+}
+
+/// Decode a query string key or value: `+` is a space and `%XX` sequences are decoded
+pub(crate) fn decode_query(text: &str) -> String {
+    decode_bytes(text, true)
+}
+
+fn decode_bytes(text: &str, plus_as_space: bool) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::<u8>::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let b = bytes[index];
+        if b == b'%' {
+            if let Some(&[high, low]) = bytes.get(index + 1..index + 3) {
+                if high.is_ascii_hexdigit() && low.is_ascii_hexdigit() {
+                    decoded.push(hex_value(high) << 4 | hex_value(low));
+                    index += 3;
+                    continue;
+                }
+            }
+        }
+        decoded.push(if plus_as_space && b == b'+' { b' ' } else { b });
+        index += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn hex_value(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        b'a'..=b'f' => digit - b'a' + 10,
+        _ => digit - b'A' + 10,
+    }
+}
@@ -0,0 +1,9 @@
+/// `len` random bytes for keys, nonces and identifiers, from the random number generator of the operating system.
+/// Panics when the operating system cannot provide them: there is no weaker source that is safe for secrets.
+pub(crate) fn bytes(len: usize) -> Vec<u8> {
+    let mut buf = vec![0u8; len];
+    if let Err(e) = getrandom::getrandom(&mut buf) {
+        panic!("The random number generator of the operating system failed: {}", e);
+    }
+    buf
+}
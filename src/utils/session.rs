@@ -0,0 +1,744 @@
+//! Server-side sessions: the session middleware keeps the values of each client in a `SessionStore`, and the client only holds a
+//! random session ID in a cookie.
+//!
+//! Example:
+//! ```rust,ignore
+//! use speed_rs_core::utils::{session::{self, MemoryStore, SessionConfig}, MoreDetailsRequest};
+//!
+//! server.insert_middleware(session::middleware(MemoryStore::new(), SessionConfig::default()));
+//! server.insert_handler(|req, mut res| {
+//!     let session = req.session().unwrap();
+//!     if req.path() == "/login" {
+//!         // a new ID after a login, so an ID known before cannot be used to act as the user
+//!         session.rotate();
+//!         session.insert("user_id", "42");
+//!     }
+//!     res.set_status(HttpStatusStruct(200, "OK"));
+//!     res.text(format!("Hello {:?}", session.get("user_id")));
+//!     Ok((req, res))
+//! });
+//! ```
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard, RwLock, Weak},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{temp_file::TempFile, HandlerResult, HttpRequest, HttpResponse, Next};
+
+use super::{
+    cookie::{Cookie, SameSite},
+    percent, random, MoreDetailsRequest,
+};
+
+/// Random bytes of a session ID, it is written in hexadecimal
+const ID_SIZE: usize = 32;
+
+/// Settings of the session middleware.
+///
+/// Example:
+/// ```rust,ignore
+/// let mut config = SessionConfig::default();
+/// config.secure = true;
+/// config.idle_timeout = Some(Duration::from_secs(15 * 60));
+/// ```
+#[derive(Clone, Debug)]
+pub struct SessionConfig {
+    /// Name of the cookie holding the session ID. Default is `sid`.
+    pub cookie_name: String,
+    /// `Path` of the session cookie. Default is `/`.
+    pub cookie_path: String,
+    /// `Domain` of the session cookie. Default is `None` (only the host that set it).
+    pub cookie_domain: Option<String>,
+    /// Only send the session cookie over HTTPS. Default is `false`.
+    pub secure: bool,
+    /// `SameSite` of the session cookie. Default is `Lax`.
+    pub same_site: SameSite,
+    /// A session expires when it is not used for this long. Default is 30 minutes, `None` never.
+    pub idle_timeout: Option<Duration>,
+    /// A session expires this long after it was created, even when it is used. Default is 24 hours, `None` never.
+    pub absolute_timeout: Option<Duration>,
+    /// How often the expired sessions are removed from the store by a background thread. Default is 1 minute, `None` never.
+    pub sweep_interval: Option<Duration>,
+}
+
+/// Session of a request, given by `MoreDetailsRequest::session()`.
+/// It is shared by the clones of the handle, and saved to the store when the rest of the handler chain is done.
+/// A new client gets a session ID (and a cookie) only once a value is inserted.
+#[derive(Clone)]
+pub struct Session {
+    inner: Arc<Mutex<SessionState>>,
+}
+
+struct SessionState {
+    /// ID sent by the client, `None` for a new session
+    id: Option<String>,
+    record: SessionRecord,
+    rotate: bool,
+    destroy: bool,
+}
+
+/// What a `SessionStore` keeps for a session
+#[derive(Clone, Debug)]
+pub struct SessionRecord {
+    pub values: HashMap<String, String>,
+    pub created_at: SystemTime,
+    /// The session cannot be used after this time, `None` when it never expires
+    pub expires_at: Option<SystemTime>,
+}
+
+/// Storage of the sessions, shared by the threads of the server.
+/// The session IDs given to a store are 64 lowercase hexadecimal digits.
+pub trait SessionStore: Send + Sync {
+    /// Retrieve a session, `None` when it does not exist
+    fn load(&self, id: &str) -> io::Result<Option<SessionRecord>>;
+
+    /// Create or replace a session
+    fn save(&self, id: &str, record: &SessionRecord) -> io::Result<()>;
+
+    /// Remove a session, if it exists
+    fn remove(&self, id: &str) -> io::Result<()>;
+
+    /// Remove the sessions that expired before `now`
+    fn remove_expired(&self, now: SystemTime) -> io::Result<()>;
+}
+
+/// Sessions kept in memory, they are lost when the server stops
+#[derive(Default)]
+pub struct MemoryStore {
+    sessions: RwLock<HashMap<String, SessionRecord>>,
+}
+
+/// Sessions kept in a folder, one file per session
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            cookie_name: String::from("sid"),
+            cookie_path: String::from("/"),
+            cookie_domain: None,
+            secure: false,
+            same_site: SameSite::Lax,
+            idle_timeout: Some(Duration::from_secs(30 * 60)),
+            absolute_timeout: Some(Duration::from_secs(24 * 3600)),
+            sweep_interval: Some(Duration::from_secs(60)),
+        }
+    }
+}
+
+impl Session {
+    fn new(id: Option<String>, record: SessionRecord) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(SessionState {
+                id,
+                record,
+                rotate: false,
+                destroy: false,
+            })),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, SessionState> {
+        self.inner.lock().unwrap()
+    }
+
+    /// ID of the session, `None` until a new session is saved
+    pub fn id(&self) -> Option<String> {
+        self.state().id.clone()
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.state().record.values.get(key).cloned()
+    }
+
+    pub fn insert(&self, key: &str, value: &str) {
+        self.state().record.values.insert(String::from(key), String::from(value));
+    }
+
+    /// Remove a value and return it, e.g. to read a flash message once
+    pub fn remove(&self, key: &str) -> Option<String> {
+        self.state().record.values.remove(key)
+    }
+
+    /// Remove every value, the session ID is kept
+    pub fn clear(&self) {
+        self.state().record.values.clear();
+    }
+
+    /// Give the session a new ID and drop the old one, the values are kept.
+    /// Call it when the privileges of the client change (login), so that an ID known by someone else before is useless.
+    pub fn rotate(&self) {
+        self.state().rotate = true;
+    }
+
+    /// Remove the session from the store and its cookie from the client (logout)
+    pub fn destroy(&self) {
+        let mut state = self.state();
+        state.destroy = true;
+        state.record.values.clear();
+    }
+}
+
+impl SessionRecord {
+    fn new(now: SystemTime) -> Self {
+        Self {
+            values: HashMap::new(),
+            created_at: now,
+            expires_at: None,
+        }
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> io::Result<Option<SessionRecord>> {
+        Ok(self.sessions.read().unwrap().get(id).cloned())
+    }
+
+    fn save(&self, id: &str, record: &SessionRecord) -> io::Result<()> {
+        self.sessions.write().unwrap().insert(String::from(id), record.clone());
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> io::Result<()> {
+        self.sessions.write().unwrap().remove(id);
+        Ok(())
+    }
+
+    fn remove_expired(&self, now: SystemTime) -> io::Result<()> {
+        self.sessions.write().unwrap().retain(|_, record| !record.is_expired(now));
+        Ok(())
+    }
+}
+
+impl FileStore {
+    /// Keep the sessions in `dir`, it is created when it does not exist
+    pub fn new<P: Into<PathBuf>>(dir: P) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /** Path of the file of a session, `None` for an invalid ID so that it cannot point outside of the folder */
+    fn path(&self, id: &str) -> Option<PathBuf> {
+        if is_valid_id(id) {
+            Some(self.dir.join(format!("{}.session", id)))
+        } else {
+            None
+        }
+    }
+}
+
+/// A session file has a `<created at> <expires at or ->` line (seconds since the Unix epoch), then a `key=value` line for each value
+/// with the key and the value percent-encoded
+impl SessionStore for FileStore {
+    fn load(&self, id: &str) -> io::Result<Option<SessionRecord>> {
+        let path = match self.path(id) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid session file");
+
+        let mut lines = BufReader::new(file).lines();
+        let (created_at, expires_at) = parse_times(&lines.next().ok_or_else(invalid)??).ok_or_else(invalid)?;
+        let mut values = HashMap::<String, String>::new();
+        for line in lines {
+            let line = line?;
+            let (key, value) = line.split_once('=').ok_or_else(invalid)?;
+            values.insert(percent::decode(key), percent::decode(value));
+        }
+        Ok(Some(SessionRecord {
+            values,
+            created_at,
+            expires_at,
+        }))
+    }
+
+    fn save(&self, id: &str, record: &SessionRecord) -> io::Result<()> {
+        let path = self.path(id).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid session ID"))?;
+        let mut content = format!(
+            "{} {}\n",
+            unix_secs(record.created_at),
+            record.expires_at.map_or(String::from("-"), |expires_at| unix_secs(expires_at).to_string())
+        );
+        for (key, value) in record.values.iter() {
+            content.push_str(&format!("{}={}\n", percent::encode(key), percent::encode(value)));
+        }
+
+        // readers never see a half written file, and each writer has its own temporary file when requests of a client
+        // finish together
+        let (temp_file, mut file) = TempFile::create(&self.dir, &format!("{}.tmp", id))?;
+        file.write_all(content.as_bytes())?;
+        drop(file);
+        temp_file.persist(path)
+    }
+
+    fn remove(&self, id: &str) -> io::Result<()> {
+        let path = match self.path(id) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn remove_expired(&self, now: SystemTime) -> io::Result<()> {
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "session") {
+                continue;
+            }
+            let mut first_line = String::new();
+            if File::open(&path).and_then(|file| BufReader::new(file).read_line(&mut first_line)).is_err() {
+                continue;
+            }
+            let expired = parse_times(first_line.trim_end()).is_some_and(|(_, expires_at)| expires_at.is_some_and(|expires_at| expires_at <= now));
+            if expired {
+                let _ = fs::remove_file(path);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The session middleware, insert it with `HttpServer::insert_middleware()` or `Router::layer()`.
+/// The handlers after it get the session of the request from `MoreDetailsRequest::session()`.
+///
+/// When `config.sweep_interval` is set, a background thread removes the expired sessions from the store.
+/// It stops once the middleware is dropped.
+///
+/// Concurrent requests of the same client each work on their own copy of the session, the last one to finish is saved.
+pub fn middleware<S>(store: S, config: SessionConfig) -> impl Fn(HttpRequest, HttpResponse, Next) -> HandlerResult + Send + Sync + 'static
+            where S: SessionStore + 'static {
+    let store: Arc<dyn SessionStore> = Arc::new(store);
+    if let Some(interval) = config.sweep_interval {
+        spawn_sweeper(Arc::downgrade(&store), interval);
+    }
+
+    move |mut req, res, next| {
+        let now = SystemTime::now();
+        let sent_id = req.cookie(&config.cookie_name).filter(|id| is_valid_id(id));
+        let loaded = match &sent_id {
+            Some(id) => match store.load(id) {
+                Ok(record) => record.map(|record| (id.clone(), record)),
+                Err(e) => return Err((req, res, Box::new(e))),
+            },
+            None => None,
+        };
+        let session = match loaded {
+            Some((id, record)) if !record.is_expired(now) => Session::new(Some(id), record),
+            Some((id, _)) => {
+                if let Err(e) = store.remove(&id) {
+                    return Err((req, res, Box::new(e)));
+                }
+                Session::new(None, SessionRecord::new(now))
+            }
+            None => Session::new(None, SessionRecord::new(now)),
+        };
+        req.session = Some(session.clone());
+
+        let (req, mut res) = next.run(req, res)?;
+        if let Err(e) = save(store.as_ref(), &config, &session, sent_id, &mut res) {
+            return Err((req, res, Box::new(e)));
+        }
+        Ok((req, res))
+    }
+}
+
+/** Save the session after the handlers, and set or remove the session cookie */
+fn save(store: &dyn SessionStore, config: &SessionConfig, session: &Session, sent_id: Option<String>, res: &mut HttpResponse) -> io::Result<()> {
+    let now = SystemTime::now();
+    let mut state = session.state();
+
+    if state.destroy {
+        if let Some(id) = state.id.take() {
+            store.remove(&id)?;
+        }
+        if sent_id.is_some() {
+            res.add_cookie(session_cookie(config, Cookie::removal(&config.cookie_name)));
+        }
+        return Ok(());
+    }
+
+    if state.id.is_none() && state.record.values.is_empty() {
+        // nothing to remember about this client
+        return Ok(());
+    }
+    let rotated = state.rotate;
+    if rotated {
+        if let Some(id) = state.id.take() {
+            store.remove(&id)?;
+        }
+        state.rotate = false;
+    }
+    let id = match &state.id {
+        Some(id) => id.clone(),
+        None => {
+            let id = new_id();
+            state.id = Some(id.clone());
+            // a rotated session keeps its absolute expiry
+            if !rotated {
+                state.record.created_at = now;
+            }
+            id
+        }
+    };
+
+    let idle_expiry = config.idle_timeout.map(|timeout| now + timeout);
+    let absolute_expiry = config.absolute_timeout.map(|timeout| state.record.created_at + timeout);
+    state.record.expires_at = match (idle_expiry, absolute_expiry) {
+        (Some(idle), Some(absolute)) => Some(idle.min(absolute)),
+        (idle, absolute) => idle.or(absolute),
+    };
+    store.save(&id, &state.record)?;
+
+    if sent_id.as_ref() != Some(&id) {
+        res.add_cookie(session_cookie(config, Cookie::new(&config.cookie_name, &id)));
+    }
+    Ok(())
+}
+
+/** Apply the cookie attributes of the configuration */
+fn session_cookie(config: &SessionConfig, cookie: Cookie) -> Cookie {
+    let cookie = cookie.path(&config.cookie_path).secure(config.secure).http_only(true).same_site(config.same_site);
+    match &config.cookie_domain {
+        Some(domain) => cookie.domain(domain),
+        None => cookie,
+    }
+}
+
+/** Remove the expired sessions every `interval` until the store is dropped */
+fn spawn_sweeper(store: Weak<dyn SessionStore>, interval: Duration) {
+    let spawned = thread::Builder::new().name(String::from("session-sweeper")).spawn(move || loop {
+        thread::sleep(interval);
+        let store = match store.upgrade() {
+            Some(store) => store,
+            None => break,
+        };
+        if let Err(e) = store.remove_expired(SystemTime::now()) {
+            eprintln!("Failed to remove expired sessions: {}", e);
+        }
+    });
+    if let Err(e) = spawned {
+        eprintln!("Failed to start the session sweeper: {}", e);
+    }
+}
+
+fn new_id() -> String {
+    random::bytes(ID_SIZE).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn is_valid_id(id: &str) -> bool {
+    id.len() == ID_SIZE * 2 && id.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs())
+}
+
+/// Parse the `<created at> <expires at or ->` line of a session file
+fn parse_times(line: &str) -> Option<(SystemTime, Option<SystemTime>)> {
+    let (created_at, expires_at) = line.split_once(' ')?;
+    let created_at = UNIX_EPOCH + Duration::from_secs(created_at.parse().ok()?);
+    let expires_at = match expires_at {
+        "-" => None,
+        secs => Some(UNIX_EPOCH + Duration::from_secs(secs.parse().ok()?)),
+    };
+    Some((created_at, expires_at))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{body::RequestBody, parser::RequestHead, ConnectionInfo};
+    use std::{env, net::SocketAddr};
+
+    /// Store shared with the test after the middleware took it
+    #[derive(Clone, Default)]
+    struct SharedStore(Arc<MemoryStore>);
+
+    impl SessionStore for SharedStore {
+        fn load(&self, id: &str) -> io::Result<Option<SessionRecord>> {
+            self.0.load(id)
+        }
+
+        fn save(&self, id: &str, record: &SessionRecord) -> io::Result<()> {
+            self.0.save(id, record)
+        }
+
+        fn remove(&self, id: &str) -> io::Result<()> {
+            self.0.remove(id)
+        }
+
+        fn remove_expired(&self, now: SystemTime) -> io::Result<()> {
+            self.0.remove_expired(now)
+        }
+    }
+
+    fn config() -> SessionConfig {
+        SessionConfig {
+            sweep_interval: None,
+            ..SessionConfig::default()
+        }
+    }
+
+    fn request(cookie: Option<&str>) -> HttpRequest {
+        let head = RequestHead {
+            method: String::from("GET"),
+            uri: String::from("/"),
+            version: String::from("HTTP/1.1"),
+            headers: cookie.map(|cookie| vec![(String::from("Cookie"), String::from(cookie))]).unwrap_or_default(),
+        };
+        let addr = SocketAddr::from(([127, 0, 0, 1], 80));
+        HttpRequest::new(head, RequestBody::Memory(Vec::new()), Vec::new(), Arc::default(), ConnectionInfo::new(1, addr, addr, 1))
+    }
+
+    /// Run the middleware around `handler` for a request with the `Cookie` header `cookie`, returns the `Set-Cookie` headers
+    fn run<M, H>(middleware: &M, cookie: Option<&str>, handler: H) -> Vec<String>
+            where M: Fn(HttpRequest, HttpResponse, Next) -> HandlerResult, H: Fn(&Session) {
+        let handle = |req: HttpRequest, res| {
+            handler(&req.session().unwrap());
+            Ok((req, res))
+        };
+        match middleware(request(cookie), HttpResponse::new(), Next { inner: &handle }) {
+            Ok((_, res)) => res.headers().get_all("Set-Cookie").map(String::from).collect(),
+            Err((_, _, e)) => panic!("{}", e),
+        }
+    }
+
+    /// ID of a `sid=...` cookie
+    fn cookie_id(set_cookie: &str) -> String {
+        String::from(set_cookie.strip_prefix("sid=").unwrap().split(';').next().unwrap())
+    }
+
+    fn record(values: &[(&str, &str)], expires_at: Option<SystemTime>) -> SessionRecord {
+        SessionRecord {
+            values: values.iter().map(|(key, value)| (String::from(*key), String::from(*value))).collect(),
+            created_at: UNIX_EPOCH + Duration::from_secs(1_000_000),
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn new_sessions_get_a_cookie_once_used() {
+        let store = SharedStore::default();
+        let middleware = middleware(store.clone(), config());
+        assert!(run(&middleware, None, |_| {}).is_empty());
+        assert!(store.0.sessions.read().unwrap().is_empty());
+
+        let cookies = run(&middleware, None, |session| session.insert("user", "42"));
+        assert_eq!(cookies.len(), 1);
+        let id = cookie_id(&cookies[0]);
+        assert!(is_valid_id(&id));
+        assert_eq!(cookies[0], format!("sid={}; Path=/; HttpOnly; SameSite=Lax", id));
+
+        let saved = store.load(&id).unwrap().unwrap();
+        assert_eq!(saved.values.get("user").map(String::as_str), Some("42"));
+        // the idle timeout comes before the absolute one
+        assert_eq!(saved.expires_at.unwrap().duration_since(saved.created_at).unwrap().as_secs() / 60, 30);
+
+        // the session is found again and the cookie is not sent twice
+        let cookie = format!("sid={}", id);
+        assert!(run(&middleware, Some(&cookie), |session| assert_eq!(session.get("user"), Some(String::from("42")))).is_empty());
+    }
+
+    #[test]
+    fn cookie_attributes_follow_the_config() {
+        let config = SessionConfig {
+            cookie_name: String::from("app"),
+            cookie_path: String::from("/app"),
+            cookie_domain: Some(String::from("example.com")),
+            secure: true,
+            same_site: SameSite::Strict,
+            ..config()
+        };
+        let middleware = middleware(MemoryStore::new(), config);
+        let cookies = run(&middleware, None, |session| session.insert("a", "1"));
+        let id = cookies[0].strip_prefix("app=").unwrap().split(';').next().unwrap();
+        assert_eq!(cookies[0], format!("app={}; Path=/app; Domain=example.com; Secure; HttpOnly; SameSite=Strict", id));
+    }
+
+    #[test]
+    fn expired_and_unknown_sessions_start_over() {
+        let store = SharedStore::default();
+        let expired = new_id();
+        store.save(&expired, &record(&[("user", "42")], Some(SystemTime::now() - Duration::from_secs(1)))).unwrap();
+        let middleware = middleware(store.clone(), config());
+
+        assert!(run(&middleware, Some(&format!("sid={}", expired)), |session| {
+            assert_eq!(session.get("user"), None);
+            assert_eq!(session.id(), None);
+        })
+        .is_empty());
+        assert!(store.load(&expired).unwrap().is_none());
+
+        for cookie in [format!("sid={}", new_id()), String::from("sid=../../etc/passwd"), String::from("sid=ABC")] {
+            run(&middleware, Some(&cookie), |session| assert_eq!(session.id(), None));
+        }
+    }
+
+    #[test]
+    fn absolute_timeout_caps_the_expiry() {
+        let store = SharedStore::default();
+        let id = new_id();
+        let created_at = SystemTime::now() - Duration::from_secs(24 * 3600 - 60);
+        store.save(&id, &SessionRecord { created_at, ..record(&[("user", "42")], None) }).unwrap();
+        let middleware = middleware(store.clone(), config());
+
+        run(&middleware, Some(&format!("sid={}", id)), |_| {});
+        let saved = store.load(&id).unwrap().unwrap();
+        assert_eq!(saved.expires_at, Some(created_at + Duration::from_secs(24 * 3600)));
+    }
+
+    #[test]
+    fn rotate_moves_the_values_to_a_new_id() {
+        let store = SharedStore::default();
+        let old = new_id();
+        let expires_at = SystemTime::now() + Duration::from_secs(60);
+        store.save(&old, &SessionRecord { created_at: SystemTime::now(), ..record(&[("cart", "3")], Some(expires_at)) }).unwrap();
+        let created_at = store.load(&old).unwrap().unwrap().created_at;
+        let middleware = middleware(store.clone(), config());
+
+        let cookies = run(&middleware, Some(&format!("sid={}", old)), |session| {
+            session.rotate();
+            session.insert("user", "42");
+        });
+        let new = cookie_id(&cookies[0]);
+        assert_ne!(new, old);
+        assert!(store.load(&old).unwrap().is_none());
+        let saved = store.load(&new).unwrap().unwrap();
+        assert_eq!((saved.values.len(), saved.created_at), (2, created_at));
+    }
+
+    #[test]
+    fn destroy_removes_the_session_and_its_cookie() {
+        let store = SharedStore::default();
+        let id = new_id();
+        store.save(&id, &record(&[("user", "42")], None)).unwrap();
+        let middleware = middleware(store.clone(), config());
+
+        let cookies = run(&middleware, Some(&format!("sid={}", id)), |session| session.destroy());
+        assert_eq!(cookies, vec!["sid=; Path=/; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT; HttpOnly; SameSite=Lax"]);
+        assert!(store.load(&id).unwrap().is_none());
+
+        // a new client has no cookie to remove
+        assert!(run(&middleware, None, |session| {
+            session.insert("a", "1");
+            session.destroy();
+        })
+        .is_empty());
+        assert!(store.0.sessions.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn memory_store_removes_expired_sessions() {
+        let store = MemoryStore::new();
+        let now = SystemTime::now();
+        let (expired, valid, forever) = (new_id(), new_id(), new_id());
+        store.save(&expired, &record(&[], Some(now))).unwrap();
+        store.save(&valid, &record(&[], Some(now + Duration::from_secs(1)))).unwrap();
+        store.save(&forever, &record(&[], None)).unwrap();
+        store.remove_expired(now).unwrap();
+        assert!(store.load(&expired).unwrap().is_none());
+        assert!(store.load(&valid).unwrap().is_some());
+        assert!(store.load(&forever).unwrap().is_some());
+    }
+
+    /// `FileStore` in a new folder, removed by the test
+    fn file_store(name: &str) -> (FileStore, PathBuf) {
+        let dir = env::temp_dir().join(format!("speed-rs-sessions-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        (FileStore::new(&dir).unwrap(), dir)
+    }
+
+    #[test]
+    fn file_store_round_trip() {
+        let (store, dir) = file_store("round-trip");
+        let id = new_id();
+        let expires_at = UNIX_EPOCH + Duration::from_secs(2_000_000);
+        store.save(&id, &record(&[("a=b", "line\nbreak; 100%"), ("empty", ""), ("\u{e9}", "\u{20ac}")], Some(expires_at))).unwrap();
+
+        let loaded = store.load(&id).unwrap().unwrap();
+        assert_eq!(loaded.values, record(&[("a=b", "line\nbreak; 100%"), ("empty", ""), ("\u{e9}", "\u{20ac}")], None).values);
+        assert_eq!((loaded.created_at, loaded.expires_at), (UNIX_EPOCH + Duration::from_secs(1_000_000), Some(expires_at)));
+
+        store.save(&id, &record(&[], None)).unwrap();
+        assert_eq!(store.load(&id).unwrap().unwrap().expires_at, None);
+        store.remove(&id).unwrap();
+        assert!(store.load(&id).unwrap().is_none());
+        store.remove(&id).unwrap();
+
+        // IDs are never used as paths outside of the folder
+        assert!(store.load("../secret").unwrap().is_none());
+        assert_eq!(store.save("../secret", &record(&[], None)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn file_store_removes_expired_sessions() {
+        let (store, dir) = file_store("expiry");
+        let now = SystemTime::now();
+        let (expired, valid) = (new_id(), new_id());
+        store.save(&expired, &record(&[], Some(now - Duration::from_secs(1)))).unwrap();
+        store.save(&valid, &record(&[], Some(now + Duration::from_secs(60)))).unwrap();
+        fs::write(dir.join("notes.txt"), "not a session").unwrap();
+
+        store.remove_expired(now).unwrap();
+        assert!(store.load(&expired).unwrap().is_none());
+        assert!(store.load(&valid).unwrap().is_some());
+        assert!(dir.join("notes.txt").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn file_store_concurrent_saves_of_a_session() {
+        let (store, dir) = file_store("concurrent");
+        let store = Arc::new(store);
+        let id = new_id();
+        let writers: Vec<_> = (0..8)
+            .map(|writer| {
+                let (store, id) = (Arc::clone(&store), id.clone());
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        let value = writer.to_string().repeat(1000);
+                        store.save(&id, &record(&[("writer", &value)], None)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        // the file holds one whole write and no temporary file is left
+        let value = store.load(&id).unwrap().unwrap().values.remove("writer").unwrap();
+        assert_eq!(value.len(), 1000);
+        assert!(value.chars().all(|c| value.starts_with(c)));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
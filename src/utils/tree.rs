@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+
+use super::pattern::Segment;
+
+/// Route of a leaf: the method it handles (`None` for every method) and its index in `Router::routes`
+type Leaf = (Option<String>, usize);
+
+/// Segment trie of the route patterns of a `Router`.
+/// Lookups walk the path once and prefer static children over `:name` children over wildcards, which gives the
+/// precedence rules of the router without comparing the request with every route.
+#[derive(Default)]
+pub(crate) struct RouteTree {
+    root: Node,
+}
+
+#[derive(Default)]
+struct Node {
+    statics: HashMap<String, Node>,
+    param: Option<Box<Node>>,
+    /// Routes whose pattern ends at this node
+    routes: Vec<Leaf>,
+    /// Routes with an optional segment whose pattern ends at this node, they are less specific than `routes`
+    optional_routes: Vec<Leaf>,
+    /// Routes whose pattern ends with a wildcard after this node
+    wildcard_routes: Vec<Leaf>,
+}
+
+impl RouteTree {
+    /// Add the route `index` for a variant of its pattern without optional segments, `optional` tells whether the pattern had some.
+    /// Returns the index of an already added route handling the same method for the same paths.
+    pub(crate) fn insert(&mut self, segments: &[Segment], optional: bool, method: Option<&str>, index: usize) -> Result<(), usize> {
+        let mut node = &mut self.root;
+        let mut wildcard = false;
+        for segment in segments {
+            match segment {
+                Segment::Static(text) => node = node.statics.entry(text.clone()).or_default(),
+                Segment::Param(_) | Segment::Optional(_) => node = node.param.get_or_insert_with(Box::default),
+                Segment::Wildcard(_) => wildcard = true,
+            }
+        }
+        let leaves = if wildcard {
+            &mut node.wildcard_routes
+        } else if optional {
+            &mut node.optional_routes
+        } else {
+            &mut node.routes
+        };
+
+        if let Some((_, conflict)) = leaves.iter().find(|(other, _)| other.as_deref() == method) {
+            return Err(*conflict);
+        }
+        // routes of one method are tried before the routes of every method
+        let position = if method.is_some() {
+            leaves.iter().position(|(other, _)| other.is_none()).unwrap_or(leaves.len())
+        } else {
+            leaves.len()
+        };
+        leaves.insert(position, (method.map(String::from), index));
+        Ok(())
+    }
+
+    /// Routes whose pattern matches the decoded segments of a path with the methods they handle, grouped from the most to the least specific patterns.
+    /// The routes of one method come before the `Route::all` routes in a group.
+    pub(crate) fn lookup(&self, parts: &[&str]) -> Vec<&[Leaf]> {
+        let mut found = Vec::new();
+        self.root.collect(parts, &mut found);
+        found.retain(|leaves| !leaves.is_empty());
+        found
+    }
+}
+
+impl Node {
+    fn collect<'a>(&'a self, parts: &[&str], found: &mut Vec<&'a [Leaf]>) {
+        match parts.split_first() {
+            None => {
+                found.push(&self.routes);
+                found.push(&self.optional_routes);
+            }
+            Some((part, rest)) => {
+                if let Some(child) = self.statics.get(*part) {
+                    child.collect(rest, found);
+                }
+                if let Some(child) = &self.param {
+                    if !part.is_empty() {
+                        child.collect(rest, found);
+                    }
+                }
+            }
+        }
+        found.push(&self.wildcard_routes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::pattern::{split_path, RoutePattern};
+
+    fn tree(routes: &[(&str, Option<&str>)]) -> RouteTree {
+        let mut tree = RouteTree::default();
+        for (index, (path, method)) in routes.iter().enumerate() {
+            let pattern = RoutePattern::parse(path);
+            for variant in pattern.variants() {
+                tree.insert(&variant, pattern.has_optional(), *method, index).unwrap();
+            }
+        }
+        tree
+    }
+
+    fn indexes(tree: &RouteTree, path: &str) -> Vec<Vec<usize>> {
+        let parts: Vec<&str> = split_path(path).collect();
+        tree.lookup(&parts).iter().map(|leaves| leaves.iter().map(|(_, index)| *index).collect()).collect()
+    }
+
+    #[test]
+    fn groups_from_most_to_least_specific() {
+        let tree = tree(&[("/users/:id", Some("GET")), ("/users/me", Some("GET")), ("/*rest", None), ("/users/*rest", Some("GET"))]);
+        assert_eq!(indexes(&tree, "/users/me"), vec![vec![1], vec![0], vec![3], vec![2]]);
+        assert_eq!(indexes(&tree, "/users/1"), vec![vec![0], vec![3], vec![2]]);
+        assert_eq!(indexes(&tree, "/other"), vec![vec![2]]);
+    }
+
+    #[test]
+    fn method_routes_come_before_all_routes() {
+        let tree = tree(&[("/a", None), ("/a", Some("GET")), ("/a", Some("POST"))]);
+        let leaves = tree.lookup(&["a"]);
+        let methods: Vec<Option<&str>> = leaves[0].iter().map(|(method, _)| method.as_deref()).collect();
+        assert_eq!(methods, vec![Some("GET"), Some("POST"), None]);
+    }
+
+    #[test]
+    fn param_beats_optional_param() {
+        let tree = tree(&[("/posts/:id", Some("GET")), ("/posts/:page?", Some("GET")), ("/posts", Some("GET"))]);
+        assert_eq!(indexes(&tree, "/posts/2"), vec![vec![0], vec![1]]);
+        assert_eq!(indexes(&tree, "/posts"), vec![vec![2], vec![1]]);
+    }
+
+    #[test]
+    fn same_method_and_shape_conflicts() {
+        let mut tree = tree(&[("/users/:id", Some("GET"))]);
+        let pattern = RoutePattern::parse("/users/:name");
+        assert_eq!(tree.insert(&pattern.variants()[0], false, Some("GET"), 1), Err(0));
+        assert_eq!(tree.insert(&pattern.variants()[0], false, Some("POST"), 1), Ok(()));
+    }
+}